@@ -0,0 +1,426 @@
+use crate::{
+    config::AppConfig,
+    error::FatalError,
+    window::{Atoms, WindowClass, WindowSource, X11Windows},
+    xkb::{XKeyboard, MAX_XKB_GROUPS},
+};
+use anyhow::{bail, Context, Result};
+use rdev::Key;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use std::{
+    io::{Read, Write},
+    os::unix::{io::AsRawFd, net::UnixStream},
+    process::Command,
+    sync::{
+        mpsc::{self, Receiver, TryRecvError},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    thread,
+    time::Duration,
+};
+use x11rb::{
+    connection::Connection,
+    errors::ConnectionError,
+    protocol::{
+        xkb::{StatePart, ID},
+        xproto::*,
+        Event as X11Event,
+    },
+    rust_connection::RustConnection,
+};
+
+/// What the backend reports to the main loop.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BackendEvent {
+    /// The focused window changed, `None` when nothing has focus
+    FocusChanged(Option<u32>),
+    /// The layout group changed, by us or by the user
+    GroupChanged(u8),
+    /// The focused window changed its title, as browsers do on a tab switch
+    Retitled(u32),
+    /// Windows were mapped or closed, see `client_windows`
+    ClientListChanged,
+}
+
+/// Everything the switcher needs from the display server. The mapping logic
+/// only talks to this trait, so another session type needs just a new
+/// implementation.
+pub trait LayoutBackend: WindowSource + Send + Sync {
+    fn current_layout(&self) -> Result<u8>;
+    fn set_layout(&self, group: u8) -> Result<()>;
+    /// Switches the layout of one keyboard only.
+    fn set_layout_on(&self, device_id: u16, group: u8) -> Result<()>;
+    /// Switches by pressing the given keys, for when `set_layout` is ignored.
+    fn set_layout_with_keys(&self, group: u8, keys: &[Key]) -> Result<()>;
+    fn group_count(&self) -> Result<u8>;
+    /// X11 keycode of a key in the current keyboard mapping.
+    fn keycode_of(&self, key: Key) -> Result<Option<u8>>;
+    /// Short layout names ("us", "ru") in group order.
+    fn layout_symbols(&self) -> Result<Vec<String>>;
+    /// Full layout names ("English (US)") in group order.
+    fn layout_names(&self) -> Result<Vec<String>>;
+    fn resolve_layout_name(&self, name: &str) -> Option<u8>;
+    /// Layouts and variants of the loaded keymap, one entry per group.
+    fn keymap_layouts(&self) -> Result<Vec<(String, String)>>;
+    /// Replaces the keymap groups with the given layouts and variants.
+    fn load_keymap_layouts(&self, layouts: &[(String, String)]) -> Result<()>;
+    fn active_window(&self) -> Option<u32>;
+    /// Windows the window manager currently manages, on every screen.
+    fn client_windows(&self) -> Vec<u32>;
+    /// EWMH-style window types ("normal", "dock", "dialog").
+    fn window_types(&self, window_id: u32) -> Vec<String>;
+    /// Starts delivering focus, title and group changes to `next_event`.
+    fn watch(&self) -> Result<()>;
+    /// Waits up to `timeout` for the next event. A signal ends the wait early.
+    fn next_event(&self, timeout: Duration) -> Result<Option<BackendEvent>>;
+}
+
+/// Picks the backend for the current session. Only X11 exists so far.
+pub fn connect(config: &AppConfig) -> Result<Box<dyn LayoutBackend>> {
+    Ok(Box::new(X11Backend::connect(config.device_id)?))
+}
+
+/// A backend for one-off layout queries such as the status line. Connecting
+/// has no side effects, nothing is watched until `watch` is called.
+pub fn connect_query(config: &AppConfig) -> Result<Box<dyn LayoutBackend>> {
+    connect(config)
+}
+
+pub struct X11Backend {
+    conn: Arc<RustConnection>,
+    screen_num: usize,
+    atoms: Atoms,
+    windows: X11Windows,
+    xkb: XKeyboard,
+    /// Root of the screen that last reported a focus change
+    active_root: Mutex<Option<Window>>,
+    /// Focused window whose title changes are selected
+    titled_window: Mutex<Option<Window>>,
+    /// Set up by `watch`
+    events: Mutex<Option<EventQueue>>,
+}
+
+/// X11 events read by a thread blocked in `wait_for_event`. Round trips made
+/// by other threads move events from the socket into x11rb's own queue, so
+/// polling the socket could sleep through an event that already arrived. The
+/// thread writes to `wake` after every event instead, and so do SIGINT and
+/// SIGTERM.
+struct EventQueue {
+    receiver: Receiver<Result<X11Event, ConnectionError>>,
+    wake: UnixStream,
+}
+
+impl X11Backend {
+    pub fn connect(device_id: Option<u16>) -> Result<Self> {
+        let (conn, screen_num) = x11rb::connect(None).context(FatalError::XConnection)?;
+        let conn = Arc::new(conn);
+        let atoms = Atoms::new(conn.as_ref())
+            .context("Failed to intern X11 atoms")?
+            .reply()
+            .context("Failed to get X11 atoms reply")?;
+        let device_id = device_id.unwrap_or(ID::USE_CORE_KBD.into());
+        let xkb = XKeyboard::new(Arc::clone(&conn), device_id)?;
+        let root = conn.setup().roots[screen_num].root;
+        let windows = X11Windows::new(Arc::clone(&conn), root, atoms);
+
+        Ok(Self {
+            conn,
+            screen_num,
+            atoms,
+            windows,
+            xkb,
+            active_root: Mutex::new(None),
+            titled_window: Mutex::new(None),
+            events: Mutex::new(None),
+        })
+    }
+
+    fn translate(&self, event: X11Event) -> Option<BackendEvent> {
+        match event {
+            X11Event::PropertyNotify(ev) if ev.atom == self.atoms._NET_ACTIVE_WINDOW => {
+                *self
+                    .active_root
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner) = Some(ev.window);
+                let window = self.windows.active_window_on(ev.window);
+                self.watch_title(window);
+                Some(BackendEvent::FocusChanged(window))
+            }
+            X11Event::PropertyNotify(ev)
+                if (ev.atom == self.atoms._NET_WM_NAME
+                    || ev.atom == Atom::from(AtomEnum::WM_NAME))
+                    && *self.titled() == Some(ev.window) =>
+            {
+                Some(BackendEvent::Retitled(ev.window))
+            }
+            X11Event::PropertyNotify(ev) if ev.atom == self.atoms._NET_CLIENT_LIST => {
+                Some(BackendEvent::ClientListChanged)
+            }
+            X11Event::XkbStateNotify(ev) if ev.changed.contains(StatePart::GROUP_STATE) => {
+                Some(BackendEvent::GroupChanged(ev.group.into()))
+            }
+            _ => None,
+        }
+    }
+
+    fn titled(&self) -> MutexGuard<'_, Option<Window>> {
+        self.titled_window
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Moves the title subscription to the newly focused window. Only the
+    /// focused window is watched, so retitling in the background costs nothing.
+    /// The previous window may already be gone, its error event is ignored.
+    fn watch_title(&self, window: Option<Window>) {
+        let mut titled = self.titled();
+        if *titled == window {
+            return;
+        }
+
+        if let Some(previous) = titled.take() {
+            let _ = self.conn.change_window_attributes(
+                previous,
+                &ChangeWindowAttributesAux::default().event_mask(EventMask::NO_EVENT),
+            );
+        }
+        if let Some(window) = window {
+            let _ = self.conn.change_window_attributes(
+                window,
+                &ChangeWindowAttributesAux::default().event_mask(EventMask::PROPERTY_CHANGE),
+            );
+        }
+        let _ = self.conn.flush();
+        *titled = window;
+    }
+
+    fn start_event_thread(&self) -> Result<EventQueue> {
+        let (wake, wake_sender) = UnixStream::pair().context("Failed to create wake socket")?;
+        wake.set_nonblocking(true)?;
+        wake_sender.set_nonblocking(true)?;
+        for signal in [SIGINT, SIGTERM] {
+            signal_hook::low_level::pipe::register(signal, wake_sender.try_clone()?)
+                .context("Failed to install signal handler")?;
+        }
+
+        let conn = Arc::clone(&self.conn);
+        let (sender, receiver) = mpsc::channel();
+        thread::Builder::new()
+            .name("x11-events".into())
+            .spawn(move || loop {
+                let event = conn.wait_for_event();
+                let failed = event.is_err();
+                if sender.send(event).is_err() {
+                    break;
+                }
+                // A full socket already has a wakeup pending
+                let _ = (&wake_sender).write(&[0]);
+                if failed {
+                    break;
+                }
+            })
+            .context("Failed to start X11 event thread")?;
+
+        Ok(EventQueue { receiver, wake })
+    }
+}
+
+impl EventQueue {
+    /// Blocks until the event thread or a signal writes to the wake socket, or
+    /// the timeout expires.
+    fn wait(&self, timeout: Duration) {
+        let mut fds = [libc::pollfd {
+            fd: self.wake.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        }];
+        let timeout = i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX);
+
+        // SAFETY: `fds` is a valid array of one pollfd for the whole call
+        unsafe {
+            libc::poll(fds.as_mut_ptr(), 1, timeout);
+        }
+
+        let mut buf = [0; 64];
+        while matches!((&self.wake).read(&mut buf), Ok(n) if n > 0) {}
+    }
+}
+
+impl WindowSource for X11Backend {
+    fn window_class_pair(&self, window_id: u32) -> Option<WindowClass> {
+        self.windows.window_class_pair(window_id)
+    }
+
+    fn window_title(&self, window_id: u32) -> Option<String> {
+        self.windows.window_title(window_id)
+    }
+}
+
+impl LayoutBackend for X11Backend {
+    fn current_layout(&self) -> Result<u8> {
+        self.xkb.current_layout()
+    }
+
+    fn set_layout(&self, group: u8) -> Result<()> {
+        self.xkb.set_layout(group)
+    }
+
+    fn set_layout_on(&self, device_id: u16, group: u8) -> Result<()> {
+        self.xkb.set_layout_on(device_id, group)
+    }
+
+    fn set_layout_with_keys(&self, group: u8, keys: &[Key]) -> Result<()> {
+        self.xkb.set_layout_with_keys(group, keys)
+    }
+
+    fn keycode_of(&self, key: Key) -> Result<Option<u8>> {
+        self.xkb.key_to_keycode(key)
+    }
+
+    fn group_count(&self) -> Result<u8> {
+        self.xkb.group_count()
+    }
+
+    fn layout_symbols(&self) -> Result<Vec<String>> {
+        self.xkb.layout_symbols()
+    }
+
+    fn layout_names(&self) -> Result<Vec<String>> {
+        self.xkb.layout_names()
+    }
+
+    fn resolve_layout_name(&self, name: &str) -> Option<u8> {
+        self.xkb.resolve_layout_name(name)
+    }
+
+    /// Read from `_XKB_RULES_NAMES`, which setxkbmap keeps up to date.
+    fn keymap_layouts(&self) -> Result<Vec<(String, String)>> {
+        let reply = self
+            .conn
+            .get_property(
+                false,
+                self.conn.setup().roots[self.screen_num].root,
+                self.atoms._XKB_RULES_NAMES,
+                AtomEnum::STRING,
+                0,
+                1024,
+            )
+            .context("Failed to get XKB rules names")?
+            .reply()
+            .context("Failed to get XKB rules names reply")?;
+
+        // rules, model, layout, variant, options
+        let value = String::from_utf8_lossy(&reply.value);
+        let parts: Vec<&str> = value.split('\0').collect();
+        let layouts = parts.get(2).copied().unwrap_or_default();
+        let variants: Vec<&str> = parts
+            .get(3)
+            .copied()
+            .unwrap_or_default()
+            .split(',')
+            .collect();
+
+        Ok(layouts
+            .split(',')
+            .filter(|layout| !layout.is_empty())
+            .enumerate()
+            .map(|(index, layout)| {
+                let variant = variants.get(index).copied().unwrap_or_default();
+                (layout.to_string(), variant.to_string())
+            })
+            .collect())
+    }
+
+    fn load_keymap_layouts(&self, layouts: &[(String, String)]) -> Result<()> {
+        if layouts.len() > MAX_XKB_GROUPS {
+            bail!("XKB supports at most {} layouts", MAX_XKB_GROUPS);
+        }
+
+        let layout_list: Vec<&str> = layouts.iter().map(|(layout, _)| layout.as_str()).collect();
+        let variant_list: Vec<&str> = layouts
+            .iter()
+            .map(|(_, variant)| variant.as_str())
+            .collect();
+
+        let status = Command::new("setxkbmap")
+            .args(["-layout", &layout_list.join(",")])
+            .args(["-variant", &variant_list.join(",")])
+            .status()
+            .context("Failed to run setxkbmap")?;
+
+        if !status.success() {
+            bail!("setxkbmap exited with {}", status);
+        }
+        Ok(())
+    }
+
+    /// The active window on the screen that last changed focus, or on the
+    /// default screen before any focus change was seen.
+    fn active_window(&self) -> Option<u32> {
+        let active_root = *self
+            .active_root
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        match active_root {
+            Some(root) => self.windows.active_window_on(root),
+            None => self.windows.active_window(),
+        }
+    }
+
+    fn client_windows(&self) -> Vec<u32> {
+        self.conn
+            .setup()
+            .roots
+            .iter()
+            .flat_map(|screen| self.windows.client_windows_on(screen.root))
+            .collect()
+    }
+
+    fn window_types(&self, window_id: u32) -> Vec<String> {
+        self.windows.window_types(window_id)
+    }
+
+    fn watch(&self) -> Result<()> {
+        for screen in &self.conn.setup().roots {
+            self.conn.change_window_attributes(
+                screen.root,
+                &ChangeWindowAttributesAux::default().event_mask(EventMask::PROPERTY_CHANGE),
+            )?;
+        }
+        self.xkb.select_group_events()?;
+        self.conn.flush()?;
+
+        let mut events = self.events.lock().unwrap_or_else(PoisonError::into_inner);
+        if events.is_none() {
+            *events = Some(self.start_event_thread()?);
+        }
+        drop(events);
+
+        self.watch_title(self.active_window());
+        Ok(())
+    }
+
+    fn next_event(&self, timeout: Duration) -> Result<Option<BackendEvent>> {
+        let events = self.events.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(events) = events.as_ref() else {
+            bail!("X11 events are not watched");
+        };
+        let mut waited = false;
+
+        loop {
+            match events.receiver.try_recv() {
+                Ok(event) => {
+                    if let Some(event) = self.translate(event?) {
+                        return Ok(Some(event));
+                    }
+                }
+                Err(TryRecvError::Disconnected) => bail!("X11 event thread stopped"),
+                Err(TryRecvError::Empty) if waited => return Ok(None),
+                Err(TryRecvError::Empty) => {
+                    events.wait(timeout);
+                    waited = true;
+                }
+            }
+        }
+    }
+}
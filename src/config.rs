@@ -0,0 +1,1036 @@
+use crate::{
+    hotkey,
+    window::{WindowClass, WindowSource},
+};
+use anyhow::{bail, Context, Result};
+use glob::{MatchOptions, Pattern};
+use log::{error, info};
+use rdev::Key;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// Schema version written by this build. Bump it together with a new step in
+/// `AppConfig::migrate`.
+pub const CONFIG_VERSION: u32 = 2;
+
+/// Keysym names `xtest_switch_keys` took before version 2 and the hotkey
+/// names replacing them.
+const XTEST_KEY_RENAMES: &[(&str, &str)] = &[
+    ("shift_l", "lshift"),
+    ("shift_r", "rshift"),
+    ("control_l", "lctrl"),
+    ("control_r", "rctrl"),
+    ("alt_l", "lalt"),
+    ("alt_r", "ralt"),
+    ("super_l", "lsuper"),
+    ("super_r", "rsuper"),
+    ("caps_lock", "capslock"),
+];
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct AppConfig {
+    /// Configs written before versioning have no version and count as 0
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default)]
+    pub window_layout_map: HashMap<String, LayoutRef>,
+    #[serde(default)]
+    pub hotkeys: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<u16>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub window_device_map: HashMap<String, u16>,
+    #[serde(default)]
+    pub log_target: LogTarget,
+    #[serde(default)]
+    pub log_mode: LogMode,
+    #[serde(default)]
+    pub log_level: LogLevel,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_path: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_max_bytes: Option<u64>,
+    #[serde(default)]
+    pub remember_per_window: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub no_window_layout: Option<LayoutRef>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_layout: Option<LayoutRef>,
+    #[serde(default = "AppConfig::default_hotkey_debounce_ms")]
+    pub hotkey_debounce_ms: u64,
+    #[serde(default)]
+    pub require_multiple_layouts: bool,
+    #[serde(default)]
+    pub xtest_fallback: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub xtest_switch_keys: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub splash_classes: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub layout_switch_keys: Vec<String>,
+    #[serde(default = "AppConfig::default_add_window_types")]
+    pub add_window_types: Vec<String>,
+    #[serde(default)]
+    pub status_line_format: StatusFormat,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    #[serde(skip)]
+    pub included: IncludedMappings,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub window_title_map: HashMap<String, LayoutRef>,
+    #[serde(skip)]
+    pub glob_patterns: Vec<(String, Pattern)>,
+    #[serde(skip)]
+    pub title_patterns: Vec<(TitlePattern, LayoutRef)>,
+    /// Version the file had before it was migrated on load
+    #[serde(skip)]
+    pub migrated_from: Option<u32>,
+}
+
+/// Matches what serde fills in for an empty file, so a config built in code
+/// behaves like one loaded from disk.
+impl Default for AppConfig {
+    fn default() -> Self {
+        Self {
+            version: 0,
+            window_layout_map: HashMap::new(),
+            hotkeys: HashMap::new(),
+            device_id: None,
+            window_device_map: HashMap::new(),
+            log_target: LogTarget::default(),
+            log_mode: LogMode::default(),
+            log_level: LogLevel::default(),
+            log_path: None,
+            log_max_bytes: None,
+            remember_per_window: false,
+            no_window_layout: None,
+            default_layout: None,
+            hotkey_debounce_ms: Self::default_hotkey_debounce_ms(),
+            require_multiple_layouts: false,
+            xtest_fallback: false,
+            xtest_switch_keys: Vec::new(),
+            splash_classes: Vec::new(),
+            layout_switch_keys: Vec::new(),
+            add_window_types: Self::default_add_window_types(),
+            status_line_format: StatusFormat::default(),
+            include: Vec::new(),
+            included: IncludedMappings::default(),
+            window_title_map: HashMap::new(),
+            glob_patterns: Vec::new(),
+            title_patterns: Vec::new(),
+            migrated_from: None,
+        }
+    }
+}
+
+/// A layout given either by XKB group index or by layout name ("us", "ru").
+/// Names are resolved at runtime, so they survive reordering of the layouts.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[serde(untagged)]
+pub enum LayoutRef {
+    Index(u8),
+    Name(String),
+    /// A layout that may not be loaded yet; it is added to the keymap as an
+    /// extra group while needed.
+    Variant {
+        layout: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        variant: Option<String>,
+    },
+}
+
+impl LayoutRef {
+    pub fn from_arg(arg: &str) -> Self {
+        if let Ok(index) = arg.parse() {
+            return LayoutRef::Index(index);
+        }
+
+        match arg.strip_suffix(')').and_then(|arg| arg.split_once('(')) {
+            Some((layout, variant)) => LayoutRef::Variant {
+                layout: layout.to_string(),
+                variant: Some(variant.to_string()),
+            },
+            None => LayoutRef::Name(arg.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for LayoutRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LayoutRef::Index(index) => write!(f, "{}", index),
+            LayoutRef::Name(name) => write!(f, "{}", name),
+            LayoutRef::Variant {
+                layout,
+                variant: Some(variant),
+            } => write!(f, "{}({})", layout, variant),
+            LayoutRef::Variant {
+                layout,
+                variant: None,
+            } => write!(f, "{}", layout),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TitlePattern(Regex);
+
+impl PartialEq for TitlePattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+/// Entries merged in from `include` files. They are kept apart so that saving
+/// the main config does not copy them into it.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct IncludedMappings {
+    pub window_layout_map: HashMap<String, LayoutRef>,
+    pub window_device_map: HashMap<String, u16>,
+    pub hotkeys: HashMap<String, String>,
+    /// Included layouts the main file overrides, in effect again once the
+    /// override is removed
+    pub shadowed_layouts: HashMap<String, LayoutRef>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum LogTarget {
+    #[default]
+    File,
+    Stderr,
+    Syslog,
+}
+
+/// What to do with the log file left by the previous run.
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum LogMode {
+    Truncate,
+    #[default]
+    Append,
+    /// Keep the previous log as `<log>.<unix time>` and start a new one
+    Rotate,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    #[default]
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum StatusFormat {
+    Index,
+    #[default]
+    Short,
+    Full,
+}
+
+impl StatusFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "index" => Some(Self::Index),
+            "short" => Some(Self::Short),
+            "full" => Some(Self::Full),
+            _ => None,
+        }
+    }
+}
+
+impl AppConfig {
+    pub fn load_from_file(path: &PathBuf) -> Result<Self> {
+        if path.exists() {
+            Self::load_existing(path)
+        } else {
+            let config = AppConfig {
+                version: CONFIG_VERSION,
+                window_layout_map: HashMap::new(),
+                hotkeys: HashMap::from([
+                    ("add_window".into(), "ctrl shift q".into()),
+                    ("remove_window".into(), "ctrl shift w".into()),
+                ]),
+                ..Default::default()
+            };
+            config.save_to_file(path)?;
+            Ok(config)
+        }
+    }
+
+    /// Like `load_from_file`, but a missing file is an error instead of being
+    /// created. Reloads use this: an editor saving by rename briefly removes
+    /// the file, and that must not reset the live config to the defaults.
+    pub fn load_existing(path: &PathBuf) -> Result<Self> {
+        let mut config = Self::read_file(path)?;
+        let migrated = config.migrate()?;
+        config.merge_includes(path)?;
+        config.compile_patterns()?;
+        // Only a config that loads cleanly replaces the user's file
+        if migrated {
+            config.save_to_file(path)?;
+        }
+        Ok(config)
+    }
+
+    /// Upgrades a config written for an older schema one version at a time.
+    /// Returns whether anything changed, so running it again is a no-op. Steps
+    /// may add or convert settings but must keep every mapping and hotkey.
+    /// Files from a newer build are refused rather than saved back without the
+    /// settings this build does not know.
+    fn migrate(&mut self) -> Result<bool> {
+        let from = self.version;
+
+        if from > CONFIG_VERSION {
+            bail!(
+                "Config version {} is newer than supported {}, update NSKeyboardLayoutSwitcher",
+                from,
+                CONFIG_VERSION
+            );
+        }
+
+        while self.version < CONFIG_VERSION {
+            match self.version {
+                // Fields added since then already have their serde defaults;
+                // saving writes them out explicitly
+                0 => {}
+                // XTEST keys used X keysym names, they now take hotkey names
+                1 => {
+                    for key in &mut self.xtest_switch_keys {
+                        if let Some(&(_, renamed)) = XTEST_KEY_RENAMES
+                            .iter()
+                            .find(|(old, _)| old.eq_ignore_ascii_case(key))
+                        {
+                            *key = renamed.to_string();
+                        }
+                    }
+                }
+                version => bail!("No migration from config version {}", version),
+            }
+            self.version += 1;
+        }
+
+        if self.version == from {
+            return Ok(false);
+        }
+        self.migrated_from = Some(from);
+        Ok(true)
+    }
+
+    /// Called once the logger is up, which needs the config to be loaded first.
+    pub fn log_version(&self, path: &Path) {
+        match self.migrated_from {
+            Some(from) => info!(
+                "Migrated config {} from version {} to {}",
+                path.display(),
+                from,
+                self.version
+            ),
+            None => info!("Loaded config {} version {}", path.display(), self.version),
+        }
+    }
+
+    fn default_hotkey_debounce_ms() -> u64 {
+        1000
+    }
+
+    fn default_add_window_types() -> Vec<String> {
+        vec!["normal".to_string()]
+    }
+
+    pub fn read_file(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file: {}", path.display()))?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse config file: {}", path.display()))
+    }
+
+    fn merge_includes(&mut self, path: &Path) -> Result<()> {
+        let mut included = IncludedMappings::default();
+        let mut chain = vec![path.canonicalize().unwrap_or_else(|_| path.to_path_buf())];
+        let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+        Self::collect_includes(base_dir, path, &self.include, &mut chain, &mut included)?;
+
+        // The main file always wins over anything it includes
+        let (shadowed, own) = included
+            .window_layout_map
+            .drain()
+            .partition(|(class, _)| self.window_layout_map.contains_key(class));
+        included.shadowed_layouts = shadowed;
+        included.window_layout_map = own;
+        included
+            .window_device_map
+            .retain(|class, _| !self.window_device_map.contains_key(class));
+        included
+            .hotkeys
+            .retain(|action, _| !self.hotkeys.contains_key(action));
+
+        self.window_layout_map
+            .extend(included.window_layout_map.clone());
+        self.window_device_map
+            .extend(included.window_device_map.clone());
+        self.hotkeys.extend(included.hotkeys.clone());
+        self.included = included;
+        Ok(())
+    }
+
+    /// Later includes override earlier ones, and an included file overrides
+    /// the files it includes itself. Every path, nested ones too, is relative
+    /// to `base_dir`, the directory of the main config.
+    fn collect_includes(
+        base_dir: &Path,
+        path: &Path,
+        includes: &[String],
+        chain: &mut Vec<PathBuf>,
+        included: &mut IncludedMappings,
+    ) -> Result<()> {
+        for include in includes {
+            let include_path = base_dir
+                .join(include)
+                .canonicalize()
+                .with_context(|| format!("Included config not found: {}", include))?;
+
+            if chain.contains(&include_path) {
+                bail!(
+                    "Config include cycle: {} includes {}",
+                    path.display(),
+                    include_path.display()
+                );
+            }
+
+            let nested = Self::read_file(&include_path)?;
+            chain.push(include_path.clone());
+            Self::collect_includes(base_dir, &include_path, &nested.include, chain, included)?;
+            chain.pop();
+
+            included.window_layout_map.extend(nested.window_layout_map);
+            included.window_device_map.extend(nested.window_device_map);
+            included.hotkeys.extend(nested.hotkeys);
+        }
+
+        Ok(())
+    }
+
+    /// Entries are told apart by origin: a key the main file sets is its own,
+    /// even when an include has the same value.
+    fn without_included(&self) -> Self {
+        let mut own = self.clone();
+        own.window_layout_map
+            .retain(|class, _| !self.included.window_layout_map.contains_key(class));
+        own.window_device_map
+            .retain(|class, _| !self.included.window_device_map.contains_key(class));
+        own.hotkeys
+            .retain(|action, _| !self.included.hotkeys.contains_key(action));
+        own
+    }
+
+    /// Sets a mapping in the main file. An included entry for the same key is
+    /// overridden there, the way a hand-written main file entry would.
+    pub fn set_mapping(&mut self, key: String, layout: LayoutRef) {
+        if let Some(included) = self.included.window_layout_map.remove(&key) {
+            self.included.shadowed_layouts.insert(key.clone(), included);
+        }
+        self.window_layout_map.insert(key, layout);
+    }
+
+    /// Removes a mapping from the main file. Included entries are refused: the
+    /// next reload would bring them back, so they have to go in their own file.
+    pub fn remove_mapping(&mut self, key: &str) -> Result<()> {
+        if self.included.window_layout_map.contains_key(key) {
+            bail!(
+                "Mapping for {} comes from an included config, remove it there",
+                key
+            );
+        }
+
+        self.window_layout_map.remove(key);
+        if !self.included.window_device_map.contains_key(key) {
+            self.window_device_map.remove(key);
+        }
+
+        if let Some(included) = self.included.shadowed_layouts.remove(key) {
+            info!("Mapping for {} falls back to the included one", key);
+            self.included
+                .window_layout_map
+                .insert(key.to_string(), included.clone());
+            self.window_layout_map.insert(key.to_string(), included);
+        }
+        Ok(())
+    }
+
+    pub fn glob_source(key: &str) -> Option<&str> {
+        match key.strip_prefix("glob:") {
+            Some(pattern) => Some(pattern),
+            None if key.contains(['*', '?']) => Some(key),
+            None => None,
+        }
+    }
+
+    fn compile_patterns(&mut self) -> Result<()> {
+        let mut patterns = Vec::new();
+
+        for key in self.window_layout_map.keys() {
+            if let Some(source) = Self::glob_source(key) {
+                let pattern = Pattern::new(source).with_context(|| {
+                    format!("Invalid glob pattern in window_layout_map: \"{}\"", key)
+                })?;
+                patterns.push((key.clone(), pattern));
+            }
+        }
+
+        // Longer patterns are usually more specific, so they are tried first
+        patterns.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+        self.glob_patterns = patterns;
+        Ok(())
+    }
+
+    /// Invalid expressions are logged and skipped so one typo does not
+    /// disable the rest of the title mappings.
+    pub fn compile_title_patterns(&mut self) {
+        let mut patterns: Vec<(String, Regex, LayoutRef)> = Vec::new();
+
+        for (source, layout) in &self.window_title_map {
+            match Regex::new(source) {
+                Ok(regex) => patterns.push((source.clone(), regex, layout.clone())),
+                Err(e) => error!("Invalid regex in window_title_map: \"{}\": {}", source, e),
+            }
+        }
+
+        patterns.sort_by(|(a, _, _), (b, _, _)| a.cmp(b));
+        self.title_patterns = patterns
+            .into_iter()
+            .map(|(_, regex, layout)| (TitlePattern(regex), layout))
+            .collect();
+    }
+
+    fn lookup_title_layout(&self, window_title: &str) -> Option<LayoutRef> {
+        self.title_patterns
+            .iter()
+            .find(|(pattern, _)| pattern.0.is_match(window_title))
+            .map(|(_, layout)| layout.clone())
+    }
+
+    /// The key written for exactly this window, `instance.class` before a bare
+    /// class.
+    pub fn exact_key(&self, window: &WindowClass) -> Option<&str> {
+        window
+            .qualified()
+            .and_then(|qualified| self.window_layout_map.get_key_value(&qualified))
+            .or_else(|| self.window_layout_map.get_key_value(&window.class))
+            .map(|(key, _)| key.as_str())
+    }
+
+    /// Exact keys win over globs, which are tried against both key forms.
+    fn matching_key(&self, window: &WindowClass) -> Option<&str> {
+        if let Some(key) = self.exact_key(window) {
+            return Some(key);
+        }
+
+        let options = MatchOptions {
+            case_sensitive: false,
+            ..MatchOptions::new()
+        };
+        let qualified = window.qualified();
+        let candidates: Vec<&str> = qualified
+            .as_deref()
+            .into_iter()
+            .chain([window.class.as_str()])
+            .collect();
+
+        self.glob_patterns
+            .iter()
+            .find(|(_, pattern)| {
+                candidates
+                    .iter()
+                    .any(|candidate| pattern.matches_with(candidate, options))
+            })
+            .map(|(key, _)| key.as_str())
+    }
+
+    fn lookup_layout(&self, window: &WindowClass) -> Option<LayoutRef> {
+        self.matching_key(window)
+            .and_then(|key| self.window_layout_map.get(key).cloned())
+    }
+
+    /// Class mappings take precedence; the title is only read when title
+    /// mappings are configured.
+    pub fn window_mapping(
+        &self,
+        windows: &dyn WindowSource,
+        window_id: u32,
+        window_class: Option<&WindowClass>,
+    ) -> Option<(LayoutRef, Option<u16>)> {
+        window_class
+            .and_then(|class| Some((self.lookup_layout(class)?, self.lookup_device(class))))
+            .or_else(|| {
+                if self.title_patterns.is_empty() {
+                    return None;
+                }
+                let title = windows.window_title(window_id)?;
+                Some((self.lookup_title_layout(&title)?, None))
+            })
+    }
+
+    /// Whether `add_window` may record a window of these types. An empty
+    /// `add_window_types` turns the check off.
+    /// Keys pressed through XTEST to switch layouts: `xtest_switch_keys`, or
+    /// the first of `layout_switch_keys` so the combo is only written once.
+    pub fn xtest_keys(&self) -> Option<Vec<Key>> {
+        let combo = if self.xtest_switch_keys.is_empty() {
+            self.layout_switch_keys.first()?.clone()
+        } else {
+            self.xtest_switch_keys.join(" ")
+        };
+        hotkey::combo_keys(&combo).filter(|keys| !keys.is_empty())
+    }
+
+    pub fn allows_window_types(&self, window_types: &[String]) -> bool {
+        self.add_window_types.is_empty()
+            || window_types.iter().any(|window_type| {
+                self.add_window_types
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(window_type))
+            })
+    }
+
+    pub fn is_splash_class(&self, window: &WindowClass) -> bool {
+        let qualified = window.qualified();
+        self.splash_classes.iter().any(|splash| {
+            splash.eq_ignore_ascii_case(&window.class)
+                || qualified
+                    .as_deref()
+                    .is_some_and(|qualified| splash.eq_ignore_ascii_case(qualified))
+        })
+    }
+
+    fn lookup_device(&self, window: &WindowClass) -> Option<u16> {
+        self.matching_key(window)
+            .and_then(|key| self.window_device_map.get(key).copied())
+    }
+
+    /// Writes to a temporary file and renames it over the config, so an
+    /// interrupted save never leaves a truncated config behind.
+    pub fn save_to_file(&self, path: &PathBuf) -> Result<()> {
+        let content = serde_json::to_string_pretty(&self.without_included())?;
+        let tmp_path = path.with_extension("json.tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FakeWindows(HashMap<u32, String>);
+
+    impl WindowSource for FakeWindows {
+        fn window_class_pair(&self, _window_id: u32) -> Option<WindowClass> {
+            None
+        }
+
+        fn window_title(&self, window_id: u32) -> Option<String> {
+            self.0.get(&window_id).cloned()
+        }
+    }
+
+    fn window(instance: &str, class: &str) -> WindowClass {
+        WindowClass {
+            instance: instance.into(),
+            class: class.into(),
+        }
+    }
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("nskbd-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn config_with(json: &str) -> AppConfig {
+        let mut config: AppConfig = serde_json::from_str(json).unwrap();
+        config.compile_patterns().unwrap();
+        config.compile_title_patterns();
+        config
+    }
+
+    #[test]
+    fn round_trip_serialization() {
+        let config = AppConfig {
+            window_layout_map: HashMap::from([
+                ("firefox".into(), LayoutRef::Index(1)),
+                ("telegram".into(), LayoutRef::Name("ru".into())),
+                (
+                    "libreoffice".into(),
+                    LayoutRef::Variant {
+                        layout: "de".into(),
+                        variant: Some("nodeadkeys".into()),
+                    },
+                ),
+            ]),
+            hotkeys: HashMap::from([("add_window".into(), "ctrl shift q".into())]),
+            device_id: Some(3),
+            log_mode: LogMode::Rotate,
+            status_line_format: StatusFormat::Full,
+            default_layout: Some(LayoutRef::Index(0)),
+            hotkey_debounce_ms: 250,
+            ..Default::default()
+        };
+
+        let json = serde_json::to_string_pretty(&config).unwrap();
+        let parsed: AppConfig = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, config);
+    }
+
+    #[test]
+    fn empty_config_uses_defaults() {
+        let config: AppConfig = serde_json::from_str("{}").unwrap();
+        assert_eq!(
+            config.hotkey_debounce_ms,
+            AppConfig::default_hotkey_debounce_ms()
+        );
+        assert_eq!(config.log_target, LogTarget::File);
+        assert_eq!(config.log_mode, LogMode::Append);
+        assert_eq!(config.status_line_format, StatusFormat::Short);
+        assert_eq!(config.add_window_types, vec!["normal"]);
+        assert!(config.window_layout_map.is_empty());
+    }
+
+    #[test]
+    fn default_matches_empty_file() {
+        let config: AppConfig = serde_json::from_str("{}").unwrap();
+        assert_eq!(config, AppConfig::default());
+    }
+
+    #[test]
+    fn layout_ref_forms() {
+        let map: HashMap<String, LayoutRef> =
+            serde_json::from_str(r#"{"a": 1, "b": "ru", "c": {"layout": "de"}}"#).unwrap();
+        assert_eq!(map["a"], LayoutRef::Index(1));
+        assert_eq!(map["b"], LayoutRef::Name("ru".into()));
+        assert_eq!(
+            map["c"],
+            LayoutRef::Variant {
+                layout: "de".into(),
+                variant: None
+            }
+        );
+    }
+
+    #[test]
+    fn layout_ref_from_arg() {
+        assert_eq!(LayoutRef::from_arg("2"), LayoutRef::Index(2));
+        assert_eq!(LayoutRef::from_arg("us"), LayoutRef::Name("us".into()));
+        let variant = LayoutRef::from_arg("de(nodeadkeys)");
+        assert_eq!(
+            variant,
+            LayoutRef::Variant {
+                layout: "de".into(),
+                variant: Some("nodeadkeys".into())
+            }
+        );
+        assert_eq!(variant.to_string(), "de(nodeadkeys)");
+    }
+
+    #[test]
+    fn exact_class_wins_over_globs() {
+        let config = config_with(
+            r#"{"window_layout_map": {"jetbrains-idea": 0, "jetbrains-*": 1, "*": 2}}"#,
+        );
+        assert_eq!(
+            config.lookup_layout(&window("", "jetbrains-idea")),
+            Some(LayoutRef::Index(0))
+        );
+        assert_eq!(
+            config.lookup_layout(&window("", "JetBrains-Clion")),
+            Some(LayoutRef::Index(1))
+        );
+        assert_eq!(
+            config.lookup_layout(&window("", "xterm")),
+            Some(LayoutRef::Index(2))
+        );
+    }
+
+    #[test]
+    fn add_window_type_guard() {
+        let config: AppConfig = serde_json::from_str("{}").unwrap();
+        assert!(config.allows_window_types(&["normal".into()]));
+        assert!(!config.allows_window_types(&["dock".into()]));
+        assert!(!config.allows_window_types(&["dialog".into()]));
+
+        let config = config_with(r#"{"add_window_types": ["normal", "Dialog"]}"#);
+        assert!(config.allows_window_types(&["dialog".into()]));
+
+        let config = config_with(r#"{"add_window_types": []}"#);
+        assert!(config.allows_window_types(&["desktop".into()]));
+    }
+
+    #[test]
+    fn migration_keeps_entries_and_is_idempotent() {
+        let dir = scratch_dir("migration");
+        let path = dir.join("config.json");
+        fs::write(
+            &path,
+            r#"{"window_layout_map": {"firefox": 1, "xterm": "us"}, "hotkeys": {"add_window": "ctrl q"}}"#,
+        )
+        .unwrap();
+
+        let config = AppConfig::load_from_file(&path).unwrap();
+        assert_eq!(config.migrated_from, Some(0));
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert_eq!(config.window_layout_map.len(), 2);
+        assert_eq!(config.hotkeys["add_window"], "ctrl q");
+
+        let saved = fs::read_to_string(&path).unwrap();
+        let reloaded = AppConfig::load_from_file(&path).unwrap();
+        assert_eq!(reloaded.migrated_from, None);
+        assert_eq!(reloaded.window_layout_map, config.window_layout_map);
+        assert_eq!(fs::read_to_string(&path).unwrap(), saved);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn xtest_keys_take_hotkey_names() {
+        let mut config =
+            config_with(r#"{"version": 1, "xtest_switch_keys": ["alt_l", "Shift_R", "menu"]}"#);
+        assert!(config.migrate().unwrap());
+        assert_eq!(config.xtest_switch_keys, ["lalt", "rshift", "menu"]);
+        assert_eq!(
+            config.xtest_keys(),
+            Some(vec![Key::Alt, Key::ShiftRight, Key::Unknown(135)])
+        );
+
+        let config = config_with(r#"{"layout_switch_keys": ["alt shift", "super space"]}"#);
+        assert_eq!(config.xtest_keys(), Some(vec![Key::Alt, Key::ShiftLeft]));
+        assert_eq!(AppConfig::default().xtest_keys(), None);
+    }
+
+    #[test]
+    fn newer_or_invalid_config_is_not_migrated() {
+        let dir = scratch_dir("migration-refused");
+        let path = dir.join("config.json");
+
+        let newer = format!(r#"{{"version": {}}}"#, CONFIG_VERSION + 1);
+        fs::write(&path, &newer).unwrap();
+        let error = AppConfig::load_from_file(&path).unwrap_err();
+        assert!(error.to_string().contains("newer"), "{:#}", error);
+        assert_eq!(fs::read_to_string(&path).unwrap(), newer);
+
+        let invalid = r#"{"window_layout_map": {"glob:[": 0}}"#;
+        fs::write(&path, invalid).unwrap();
+        assert!(AppConfig::load_from_file(&path).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), invalid);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reload_does_not_create_missing_file() {
+        let dir = scratch_dir("reload-missing");
+        let path = dir.join("config.json");
+
+        assert!(AppConfig::load_existing(&path).is_err());
+        assert!(!path.exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn instance_class_keys() {
+        let config = config_with(
+            r#"{"window_layout_map": {"firefox": 0, "private.firefox": 1, "crx_*.chromium": 2}}"#,
+        );
+        assert_eq!(
+            config.lookup_layout(&window("navigator", "firefox")),
+            Some(LayoutRef::Index(0))
+        );
+        assert_eq!(
+            config.lookup_layout(&window("private", "firefox")),
+            Some(LayoutRef::Index(1))
+        );
+        assert_eq!(
+            config.lookup_layout(&window("crx_abc", "chromium")),
+            Some(LayoutRef::Index(2))
+        );
+        assert_eq!(config.lookup_layout(&window("", "chromium")), None);
+        assert_eq!(
+            config.exact_key(&window("private", "firefox")),
+            Some("private.firefox")
+        );
+    }
+
+    #[test]
+    fn splash_matches_either_form() {
+        let config = config_with(r#"{"splash_classes": ["jetbrains-idea", "gimp.gimp-splash"]}"#);
+        assert!(config.is_splash_class(&window("sun-awt-x11", "jetbrains-idea")));
+        assert!(config.is_splash_class(&window("gimp", "gimp-splash")));
+        assert!(!config.is_splash_class(&window("other", "gimp-splash")));
+    }
+
+    #[test]
+    fn invalid_glob_is_an_error() {
+        let mut config: AppConfig =
+            serde_json::from_str(r#"{"window_layout_map": {"glob:[": 0}}"#).unwrap();
+        assert!(config.compile_patterns().is_err());
+    }
+
+    #[test]
+    fn window_mapping_falls_back_to_title() {
+        let config = config_with(
+            r#"{
+                "window_layout_map": {"firefox": 1},
+                "window_device_map": {"firefox": 4},
+                "window_title_map": {"Telegram$": "ru", "[": 0}
+            }"#,
+        );
+        let windows = FakeWindows(HashMap::from([(7, "Chat - Telegram".to_string())]));
+
+        assert_eq!(
+            config.window_mapping(&windows, 7, Some(&window("navigator", "firefox"))),
+            Some((LayoutRef::Index(1), Some(4)))
+        );
+        assert_eq!(
+            config.window_mapping(&windows, 7, Some(&window("xterm", "xterm"))),
+            Some((LayoutRef::Name("ru".into()), None))
+        );
+        assert_eq!(config.window_mapping(&windows, 8, None), None);
+    }
+
+    #[test]
+    fn includes_are_merged_but_not_saved() {
+        let dir = scratch_dir("includes");
+        fs::write(
+            dir.join("shared.json"),
+            r#"{"window_layout_map": {"firefox": 1, "xterm": 0}}"#,
+        )
+        .unwrap();
+        let path = dir.join("config.json");
+        fs::write(
+            &path,
+            r#"{"include": ["shared.json"], "window_layout_map": {"xterm": 1}}"#,
+        )
+        .unwrap();
+
+        let config = AppConfig::load_from_file(&path).unwrap();
+        assert_eq!(
+            config.lookup_layout(&window("", "firefox")),
+            Some(LayoutRef::Index(1))
+        );
+        assert_eq!(
+            config.lookup_layout(&window("", "xterm")),
+            Some(LayoutRef::Index(1))
+        );
+
+        config.save_to_file(&path).unwrap();
+        let saved = AppConfig::read_file(&path).unwrap();
+        assert!(!saved.window_layout_map.contains_key("firefox"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn included_entries_are_told_apart_by_origin() {
+        let dir = scratch_dir("include-origin");
+        fs::write(
+            dir.join("shared.json"),
+            r#"{"window_layout_map": {"firefox": 1, "xterm": 1, "mpv": 0}}"#,
+        )
+        .unwrap();
+        let path = dir.join("config.json");
+        fs::write(
+            &path,
+            r#"{"include": ["shared.json"], "window_layout_map": {"xterm": 1}}"#,
+        )
+        .unwrap();
+
+        let mut config = AppConfig::load_from_file(&path).unwrap();
+        assert!(config.remove_mapping("firefox").is_err());
+        assert!(config.window_layout_map.contains_key("firefox"));
+
+        // Same value as the include, but the main file set it
+        config.save_to_file(&path).unwrap();
+        let saved = AppConfig::read_file(&path).unwrap();
+        assert_eq!(
+            saved.window_layout_map.get("xterm"),
+            Some(&LayoutRef::Index(1))
+        );
+
+        // Removing the override brings back the included entry, as a reload
+        // would
+        config.set_mapping("mpv".into(), LayoutRef::Index(1));
+        config.remove_mapping("xterm").unwrap();
+        config.save_to_file(&path).unwrap();
+        let reloaded = AppConfig::load_from_file(&path).unwrap();
+        assert_eq!(reloaded.window_layout_map, config.window_layout_map);
+        assert_eq!(
+            reloaded.window_layout_map.get("mpv"),
+            Some(&LayoutRef::Index(1))
+        );
+        assert!(!AppConfig::read_file(&path)
+            .unwrap()
+            .window_layout_map
+            .contains_key("xterm"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn nested_includes_are_relative_to_main_config() {
+        let dir = scratch_dir("nested-includes");
+        fs::create_dir_all(dir.join("mappings")).unwrap();
+        fs::write(
+            dir.join("mappings/common.json"),
+            r#"{"include": ["mappings/extra.json"], "window_layout_map": {"firefox": 1}}"#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("mappings/extra.json"),
+            r#"{"window_layout_map": {"xterm": 0}}"#,
+        )
+        .unwrap();
+        let path = dir.join("config.json");
+        fs::write(&path, r#"{"include": ["mappings/common.json"]}"#).unwrap();
+
+        let config = AppConfig::load_from_file(&path).unwrap();
+        assert_eq!(
+            config.lookup_layout(&window("", "firefox")),
+            Some(LayoutRef::Index(1))
+        );
+        assert_eq!(
+            config.lookup_layout(&window("", "xterm")),
+            Some(LayoutRef::Index(0))
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn include_cycle_is_an_error() {
+        let dir = scratch_dir("cycle");
+        fs::write(dir.join("a.json"), r#"{"include": ["b.json"]}"#).unwrap();
+        fs::write(dir.join("b.json"), r#"{"include": ["a.json"]}"#).unwrap();
+
+        let error = AppConfig::load_from_file(&dir.join("a.json")).unwrap_err();
+        assert!(error.to_string().contains("cycle"), "{:#}", error);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,108 @@
+use crate::error::FatalError;
+use anyhow::{Context, Result};
+use log::error;
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::{self, ErrorKind, Write},
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+    process,
+};
+
+const PID_FILE: &str = "nskbd.pid";
+
+/// `$XDG_RUNTIME_DIR` is per user already, the `/tmp` fallback is made per
+/// user by the name.
+pub fn pid_file_path() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join(PID_FILE),
+        // SAFETY: getuid has no preconditions and cannot fail
+        None => PathBuf::from(format!("/tmp/nskbd-{}.pid", unsafe { libc::getuid() })),
+    }
+}
+
+/// Fails with `FatalError::AlreadyRunning` while the process in the PID file
+/// is alive. Stale files left by a crash are ignored.
+pub fn ensure_not_running(path: &Path) -> Result<()> {
+    match running_pid(path) {
+        Some(pid) => Err(FatalError::AlreadyRunning(pid).into()),
+        None => Ok(()),
+    }
+}
+
+fn running_pid(path: &Path) -> Option<i32> {
+    let pid: i32 = fs::read_to_string(path).ok()?.trim().parse().ok()?;
+    // SAFETY: signal 0 only checks that the process exists
+    let alive = unsafe { libc::kill(pid, 0) } == 0
+        || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM);
+    (alive && pid != process::id() as i32).then_some(pid)
+}
+
+/// Detaches from the terminal. Must run before any threads or the X
+/// connection exist, since only the calling thread survives `fork`.
+pub fn daemonize() -> Result<()> {
+    // SAFETY: the process is still single threaded
+    match unsafe { libc::fork() } {
+        -1 => return Err(io::Error::last_os_error()).context("Failed to fork"),
+        0 => {}
+        _ => process::exit(0),
+    }
+
+    // SAFETY: the child is not a process group leader, so setsid succeeds
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error()).context("Failed to start a new session");
+    }
+
+    let dev_null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .context("Failed to open /dev/null")?;
+    for fd in 0..=2 {
+        // SAFETY: both descriptors are valid for the duration of the call
+        unsafe {
+            libc::dup2(dev_null.as_raw_fd(), fd);
+        }
+    }
+
+    Ok(())
+}
+
+/// PID file of the running instance, removed again when dropped.
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Created exclusively, so two instances started at the same moment cannot
+    /// both take it.
+    pub fn create(path: &Path) -> Result<Self> {
+        let open = || OpenOptions::new().write(true).create_new(true).open(path);
+
+        let mut file = match open() {
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                ensure_not_running(path)?;
+                fs::remove_file(path).ok();
+                open()
+            }
+            result => result,
+        }
+        .with_context(|| format!("Failed to create PID file: {}", path.display()))?;
+
+        writeln!(file, "{}", process::id())
+            .with_context(|| format!("Failed to write PID file: {}", path.display()))?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            error!("Failed to remove PID file {}: {}", self.path.display(), e);
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use crate::switcher::KeyboardLayoutSwitcher;
+use log::{info, warn};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+use zbus::{blocking::connection, fdo, interface};
+
+const BUS_NAME: &str = "io.github.vladgobelen.NSkbd";
+const OBJECT_PATH: &str = "/io/github/vladgobelen/NSkbd";
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Scripting interface on the session bus. Calls go through the same paths as
+/// the hotkeys and command line flags, so the config is saved the same way.
+struct SwitcherService {
+    switcher: KeyboardLayoutSwitcher,
+}
+
+fn failed(error: anyhow::Error) -> fdo::Error {
+    fdo::Error::Failed(format!("{:#}", error))
+}
+
+#[interface(name = "io.github.vladgobelen.NSkbd")]
+impl SwitcherService {
+    fn add_current_window(&self) -> fdo::Result<()> {
+        self.switcher.add_current_window().map_err(failed)
+    }
+
+    fn remove_current_window(&self) -> fdo::Result<()> {
+        self.switcher.remove_current_window().map_err(failed)
+    }
+
+    fn set_layout(&self, group: u32) -> fdo::Result<()> {
+        let group = u8::try_from(group)
+            .map_err(|_| fdo::Error::InvalidArgs(format!("Layout {} is out of range", group)))?;
+        self.switcher.switch_to_group(group).map_err(failed)
+    }
+
+    fn get_mappings(&self) -> fdo::Result<Vec<(String, u32)>> {
+        let mappings = self.switcher.mappings().map_err(failed)?;
+        Ok(mappings
+            .into_iter()
+            .map(|(class, group)| (class, group.into()))
+            .collect())
+    }
+}
+
+/// Serves the interface on its own thread until shutdown. Without a session
+/// bus the switcher keeps working, just without D-Bus control.
+pub fn start_service(switcher: KeyboardLayoutSwitcher, shutdown: Arc<AtomicBool>) {
+    thread::spawn(move || {
+        let connection = connection::Builder::session()
+            .and_then(|builder| builder.name(BUS_NAME))
+            .and_then(|builder| builder.serve_at(OBJECT_PATH, SwitcherService { switcher }))
+            .and_then(|builder| builder.build());
+
+        let _connection = match connection {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!(
+                    "D-Bus interface is unavailable, continuing without it: {}",
+                    e
+                );
+                return;
+            }
+        };
+        info!("D-Bus interface registered as {}", BUS_NAME);
+
+        while !shutdown.load(Ordering::SeqCst) {
+            thread::sleep(SHUTDOWN_POLL_INTERVAL);
+        }
+    });
+}
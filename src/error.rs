@@ -0,0 +1,51 @@
+use thiserror::Error;
+
+/// Failures that stop the program. They are attached as context to the
+/// underlying error, and `main` turns them into distinct exit codes so
+/// supervisors can tell them apart.
+#[derive(Debug, Error)]
+pub enum FatalError {
+    #[error("Failed to connect to X11 server")]
+    XConnection,
+    #[error("XKB extension is not supported by the X server")]
+    XkbUnsupported,
+    #[error("Failed to load config")]
+    Config,
+    #[error("Failed to initialize logger")]
+    Logger,
+    #[error("Not enough keyboard layouts configured")]
+    SingleLayout,
+    #[error("Another instance is already running with PID {0}")]
+    AlreadyRunning(i32),
+}
+
+impl FatalError {
+    /// Documented in the README, keep both in sync.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Self::XConnection => 2,
+            Self::XkbUnsupported => 3,
+            Self::Config => 4,
+            Self::Logger => 5,
+            Self::SingleLayout => 6,
+            Self::AlreadyRunning(_) => 7,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Context, Result};
+
+    #[test]
+    fn found_under_further_context() {
+        let result: Result<()> = Err(anyhow!("connection refused"))
+            .context(FatalError::XConnection)
+            .context("Failed to start");
+        let error = result.unwrap_err();
+
+        let fatal = error.downcast_ref::<FatalError>().unwrap();
+        assert_eq!(fatal.exit_code(), 2);
+    }
+}
@@ -0,0 +1,611 @@
+use anyhow::{anyhow, Result};
+use log::error;
+use rdev::{listen, Event as KbdEvent, EventType, Key};
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        LazyLock, Mutex,
+    },
+};
+
+/// Key names accepted in hotkey strings.
+const KEY_NAMES: &[(&str, Key)] = &[
+    ("a", Key::KeyA),
+    ("b", Key::KeyB),
+    ("c", Key::KeyC),
+    ("d", Key::KeyD),
+    ("e", Key::KeyE),
+    ("f", Key::KeyF),
+    ("g", Key::KeyG),
+    ("h", Key::KeyH),
+    ("i", Key::KeyI),
+    ("j", Key::KeyJ),
+    ("k", Key::KeyK),
+    ("l", Key::KeyL),
+    ("m", Key::KeyM),
+    ("n", Key::KeyN),
+    ("o", Key::KeyO),
+    ("p", Key::KeyP),
+    ("q", Key::KeyQ),
+    ("r", Key::KeyR),
+    ("s", Key::KeyS),
+    ("t", Key::KeyT),
+    ("u", Key::KeyU),
+    ("v", Key::KeyV),
+    ("w", Key::KeyW),
+    ("x", Key::KeyX),
+    ("y", Key::KeyY),
+    ("z", Key::KeyZ),
+    ("0", Key::Num0),
+    ("1", Key::Num1),
+    ("2", Key::Num2),
+    ("3", Key::Num3),
+    ("4", Key::Num4),
+    ("5", Key::Num5),
+    ("6", Key::Num6),
+    ("7", Key::Num7),
+    ("8", Key::Num8),
+    ("9", Key::Num9),
+    ("f1", Key::F1),
+    ("f2", Key::F2),
+    ("f3", Key::F3),
+    ("f4", Key::F4),
+    ("f5", Key::F5),
+    ("f6", Key::F6),
+    ("f7", Key::F7),
+    ("f8", Key::F8),
+    ("f9", Key::F9),
+    ("f10", Key::F10),
+    ("f11", Key::F11),
+    ("f12", Key::F12),
+    ("space", Key::Space),
+    ("enter", Key::Return),
+    ("tab", Key::Tab),
+    ("backspace", Key::Backspace),
+    ("escape", Key::Escape),
+    ("insert", Key::Insert),
+    ("delete", Key::Delete),
+    ("home", Key::Home),
+    ("end", Key::End),
+    ("pageup", Key::PageUp),
+    ("pagedown", Key::PageDown),
+    ("up", Key::UpArrow),
+    ("down", Key::DownArrow),
+    ("left", Key::LeftArrow),
+    ("right", Key::RightArrow),
+    ("minus", Key::Minus),
+    ("equal", Key::Equal),
+    ("comma", Key::Comma),
+    ("period", Key::Dot),
+    ("slash", Key::Slash),
+    ("backslash", Key::BackSlash),
+    ("semicolon", Key::SemiColon),
+    ("quote", Key::Quote),
+    ("grave", Key::BackQuote),
+    ("leftbracket", Key::LeftBracket),
+    ("rightbracket", Key::RightBracket),
+    ("kp0", Key::Kp0),
+    ("kp1", Key::Kp1),
+    ("kp2", Key::Kp2),
+    ("kp3", Key::Kp3),
+    ("kp4", Key::Kp4),
+    ("kp5", Key::Kp5),
+    ("kp6", Key::Kp6),
+    ("kp7", Key::Kp7),
+    ("kp8", Key::Kp8),
+    ("kp9", Key::Kp9),
+    ("kpenter", Key::KpReturn),
+    ("kpplus", Key::KpPlus),
+    ("kpminus", Key::KpMinus),
+    ("kpmultiply", Key::KpMultiply),
+    ("kpdivide", Key::KpDivide),
+    ("kpdelete", Key::KpDelete),
+    ("capslock", Key::CapsLock),
+    ("numlock", Key::NumLock),
+    ("scrolllock", Key::ScrollLock),
+    ("printscreen", Key::PrintScreen),
+    ("pause", Key::Pause),
+    // rdev has no variant for the menu key, it reports the X keycode
+    ("menu", Key::Unknown(135)),
+    // Aliases, only used for parsing
+    ("-", Key::Minus),
+    ("=", Key::Equal),
+    (",", Key::Comma),
+    (".", Key::Dot),
+    ("dot", Key::Dot),
+    ("/", Key::Slash),
+    ("\\", Key::BackSlash),
+    (";", Key::SemiColon),
+    ("'", Key::Quote),
+    ("`", Key::BackQuote),
+    ("[", Key::LeftBracket),
+    ("]", Key::RightBracket),
+    ("return", Key::Return),
+    ("esc", Key::Escape),
+    ("caps", Key::CapsLock),
+    ("print", Key::PrintScreen),
+];
+
+/// Modifier names with the modifier they match in hotkeys and the key that is
+/// pressed for them through XTEST. Hotkeys do not tell left from right, the
+/// side-specific names only matter for XTEST.
+const MODIFIER_NAMES: &[(&str, &str, Key)] = &[
+    ("shift", "shift", Key::ShiftLeft),
+    ("lshift", "shift", Key::ShiftLeft),
+    ("rshift", "shift", Key::ShiftRight),
+    ("ctrl", "ctrl", Key::ControlLeft),
+    ("lctrl", "ctrl", Key::ControlLeft),
+    ("rctrl", "ctrl", Key::ControlRight),
+    ("alt", "alt", Key::Alt),
+    ("lalt", "alt", Key::Alt),
+    ("ralt", "alt", Key::AltGr),
+    ("meta", "meta", Key::MetaLeft),
+    ("super", "meta", Key::MetaLeft),
+    ("win", "meta", Key::MetaLeft),
+    ("lsuper", "meta", Key::MetaLeft),
+    ("rsuper", "meta", Key::MetaRight),
+];
+
+/// Unknown key names already reported, so a typo in a hotkey checked on every
+/// key press is logged only once.
+static REPORTED_UNKNOWN_KEYS: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(Mutex::default);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HotkeyAction {
+    AddWindow,
+    RemoveWindow,
+    SetLayout(u8),
+}
+
+impl HotkeyAction {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "add_window" => Some(Self::AddWindow),
+            "remove_window" => Some(Self::RemoveWindow),
+            _ => name
+                .strip_prefix("set_layout:")
+                .and_then(|group| group.trim().parse().ok())
+                .map(Self::SetLayout),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ModifierState {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+    pub meta: bool,
+}
+
+impl ModifierState {
+    pub fn update(&mut self, key: &Key, is_press: bool) {
+        match key {
+            Key::ShiftLeft | Key::ShiftRight => self.shift = is_press,
+            Key::ControlLeft | Key::ControlRight => self.ctrl = is_press,
+            Key::Alt | Key::AltGr => self.alt = is_press,
+            Key::MetaLeft | Key::MetaRight => self.meta = is_press,
+            _ => {}
+        }
+    }
+
+    pub fn any(&self) -> bool {
+        self.shift || self.ctrl || self.alt || self.meta
+    }
+
+    pub fn matches(&self, required_mods: &HashSet<&str>) -> bool {
+        (required_mods.contains("shift") == self.shift)
+            && (required_mods.contains("ctrl") == self.ctrl)
+            && (required_mods.contains("alt") == self.alt)
+            && (required_mods.contains("meta") == self.meta)
+    }
+}
+
+/// Keys held as seen by the listener. rdev repeats `KeyPress` while a key is
+/// held, so only the first press of a key counts as a fresh one.
+#[derive(Debug, Default)]
+pub struct KeyTracker {
+    pub pressed_keys: HashSet<Key>,
+    pub modifiers: ModifierState,
+}
+
+impl KeyTracker {
+    /// Returns `false` for auto-repeat of a key that is already held.
+    pub fn press(&mut self, key: Key) -> bool {
+        self.modifiers.update(&key, true);
+        self.pressed_keys.insert(key)
+    }
+
+    pub fn release(&mut self, key: Key) {
+        self.modifiers.update(&key, false);
+        self.pressed_keys.remove(&key);
+    }
+}
+
+/// Keyboard state published by the listener thread so the main loop can take
+/// it into account. Modifiers are atomics to keep the listener lock-free on
+/// the hot path; the pressed key set is only locked briefly per event.
+#[derive(Debug, Default)]
+pub struct InputState {
+    shift: AtomicBool,
+    ctrl: AtomicBool,
+    alt: AtomicBool,
+    meta: AtomicBool,
+    pressed_keys: Mutex<HashSet<Key>>,
+}
+
+impl InputState {
+    pub fn publish(&self, modifiers: &ModifierState, pressed_keys: &HashSet<Key>) {
+        self.shift.store(modifiers.shift, Ordering::Relaxed);
+        self.ctrl.store(modifiers.ctrl, Ordering::Relaxed);
+        self.alt.store(modifiers.alt, Ordering::Relaxed);
+        self.meta.store(modifiers.meta, Ordering::Relaxed);
+
+        if let Ok(mut shared) = self.pressed_keys.lock() {
+            shared.clone_from(pressed_keys);
+        }
+    }
+
+    pub fn modifiers(&self) -> ModifierState {
+        ModifierState {
+            shift: self.shift.load(Ordering::Relaxed),
+            ctrl: self.ctrl.load(Ordering::Relaxed),
+            alt: self.alt.load(Ordering::Relaxed),
+            meta: self.meta.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn pressed_keys(&self) -> HashSet<Key> {
+        self.pressed_keys
+            .lock()
+            .map(|keys| keys.clone())
+            .unwrap_or_default()
+    }
+}
+
+pub fn str_to_key(key_str: &str) -> Option<Key> {
+    let key_str = key_str.to_lowercase();
+    let key = KEY_NAMES
+        .iter()
+        .find(|(name, _)| *name == key_str)
+        .map(|&(_, key)| key);
+
+    if key.is_none() {
+        if let Ok(mut reported) = REPORTED_UNKNOWN_KEYS.lock() {
+            if reported.insert(key_str.clone()) {
+                error!("Unknown key name in hotkey: \"{}\"", key_str);
+            }
+        }
+    }
+
+    key
+}
+
+fn modifier(name: &str) -> Option<(&'static str, Key)> {
+    MODIFIER_NAMES
+        .iter()
+        .find(|(candidate, _, _)| *candidate == name)
+        .map(|&(_, modifier, key)| (modifier, key))
+}
+
+/// Keys of a combo in hotkey syntax, in the order they are written.
+/// `None` if any of them is unknown.
+pub fn combo_keys(combo: &str) -> Option<Vec<Key>> {
+    combo
+        .split_whitespace()
+        .map(|part| {
+            let part = part.to_lowercase();
+            modifier(&part)
+                .map(|(_, key)| key)
+                .or_else(|| str_to_key(&part))
+        })
+        .collect()
+}
+
+pub fn key_to_str(key: &Key) -> Option<&'static str> {
+    match key {
+        Key::ShiftLeft | Key::ShiftRight => Some("shift"),
+        Key::ControlLeft | Key::ControlRight => Some("ctrl"),
+        Key::Alt | Key::AltGr => Some("alt"),
+        Key::MetaLeft | Key::MetaRight => Some("meta"),
+        _ => KEY_NAMES
+            .iter()
+            .find(|(_, candidate)| candidate == key)
+            .map(|&(name, _)| name),
+    }
+}
+
+pub fn check_hotkey(
+    pressed_keys: &HashSet<Key>,
+    modifiers: &ModifierState,
+    hotkey_str: &str,
+) -> bool {
+    let parts: Vec<&str> = hotkey_str.split_whitespace().collect();
+    let mut required_mods = HashSet::new();
+    let mut required_key = None;
+
+    for part in parts {
+        let part = part.to_lowercase();
+        match modifier(&part) {
+            Some((name, _)) => required_mods.insert(name),
+            None => {
+                required_key = str_to_key(&part);
+                false
+            }
+        };
+    }
+
+    modifiers.matches(&required_mods) && required_key.is_some_and(|k| pressed_keys.contains(&k))
+}
+
+/// Matches the key the user switches layouts with outside of NSkbd. Unlike
+/// hotkeys these may consist of modifiers only ("alt shift"), which match when
+/// the last of them is pressed.
+pub fn check_switch_key(
+    pressed_keys: &HashSet<Key>,
+    modifiers: &ModifierState,
+    key: &Key,
+    combo: &str,
+) -> bool {
+    let mut required_mods = HashSet::new();
+
+    for part in combo.split_whitespace() {
+        match modifier(&part.to_lowercase()) {
+            Some((name, _)) => required_mods.insert(name),
+            None => return check_hotkey(pressed_keys, modifiers, combo),
+        };
+    }
+
+    let is_modifier = matches!(key_to_str(key), Some("shift" | "ctrl" | "alt" | "meta"));
+    !required_mods.is_empty() && is_modifier && modifiers.matches(&required_mods)
+}
+
+/// Prints every key event until interrupted, so users can find out what
+/// to write in a hotkey string. Does not need a running daemon.
+pub fn print_key_events(keycode_of: impl Fn(Key) -> Option<u8> + 'static) -> Result<()> {
+    println!("Press keys to see their hotkey names, Ctrl+C to exit");
+
+    listen(move |event: KbdEvent| {
+        let (kind, key) = match event.event_type {
+            EventType::KeyPress(key) => ("press", key),
+            EventType::KeyRelease(key) => ("release", key),
+            _ => return,
+        };
+
+        let token = key_to_str(&key).unwrap_or("-");
+        let keycode = keycode_of(key).map_or_else(|| "-".to_string(), |code| code.to_string());
+
+        println!(
+            "{:<8} key: {:<16} hotkey name: {:<10} keycode: {}",
+            kind,
+            format!("{:?}", key),
+            token,
+            keycode
+        );
+    })
+    .map_err(|e| anyhow!("Keyboard listener error: {:?}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn press(keys: &[Key]) -> (HashSet<Key>, ModifierState) {
+        let mut modifiers = ModifierState::default();
+        for key in keys {
+            modifiers.update(key, true);
+        }
+        (keys.iter().copied().collect(), modifiers)
+    }
+
+    #[test]
+    fn modifier_order_does_not_matter() {
+        let (keys, modifiers) = press(&[Key::ControlLeft, Key::ShiftLeft, Key::KeyQ]);
+        assert!(check_hotkey(&keys, &modifiers, "ctrl shift q"));
+        assert!(check_hotkey(&keys, &modifiers, "shift ctrl q"));
+    }
+
+    #[test]
+    fn hotkey_names_are_case_insensitive() {
+        let (keys, modifiers) = press(&[Key::ControlRight, Key::KeyQ]);
+        assert!(check_hotkey(&keys, &modifiers, "Ctrl Q"));
+    }
+
+    #[test]
+    fn extra_modifier_prevents_match() {
+        let (keys, modifiers) = press(&[Key::ControlLeft, Key::ShiftLeft, Key::Alt, Key::KeyQ]);
+        assert!(!check_hotkey(&keys, &modifiers, "ctrl shift q"));
+    }
+
+    #[test]
+    fn missing_key_prevents_match() {
+        let (keys, modifiers) = press(&[Key::ControlLeft, Key::ShiftLeft]);
+        assert!(!check_hotkey(&keys, &modifiers, "ctrl shift q"));
+    }
+
+    #[test]
+    fn unknown_key_never_matches() {
+        let (keys, modifiers) = press(&[Key::ControlLeft, Key::KeyQ]);
+        assert!(!check_hotkey(&keys, &modifiers, "ctrl nosuchkey"));
+        assert_eq!(str_to_key("nosuchkey"), None);
+    }
+
+    #[test]
+    fn modifier_only_hotkey_never_matches() {
+        let (keys, modifiers) = press(&[Key::ControlLeft, Key::ShiftLeft]);
+        assert!(!check_hotkey(&keys, &modifiers, "ctrl shift"));
+        assert!(!check_hotkey(&keys, &modifiers, ""));
+    }
+
+    #[test]
+    fn punctuation_and_numpad_keys() {
+        let (keys, modifiers) = press(&[Key::ControlLeft, Key::Comma]);
+        assert!(check_hotkey(&keys, &modifiers, "ctrl ,"));
+        assert!(check_hotkey(&keys, &modifiers, "ctrl comma"));
+
+        let (keys, modifiers) = press(&[Key::Alt, Key::Kp5]);
+        assert!(check_hotkey(&keys, &modifiers, "alt kp5"));
+        assert!(!check_hotkey(&keys, &modifiers, "alt 5"));
+
+        assert_eq!(key_to_str(&Key::Dot), Some("period"));
+        assert_eq!(str_to_key("PrintScreen"), Some(Key::PrintScreen));
+    }
+
+    #[test]
+    fn meta_aliases() {
+        let (keys, modifiers) = press(&[Key::MetaLeft, Key::Space]);
+        for hotkey in ["meta space", "super space", "win space"] {
+            assert!(check_hotkey(&keys, &modifiers, hotkey), "{}", hotkey);
+        }
+    }
+
+    #[test]
+    fn modifier_release_clears_state() {
+        let mut modifiers = ModifierState::default();
+        modifiers.update(&Key::ShiftLeft, true);
+        assert!(modifiers.any());
+        modifiers.update(&Key::ShiftRight, false);
+        assert!(!modifiers.any());
+    }
+
+    #[test]
+    fn modifier_only_switch_key() {
+        let (keys, modifiers) = press(&[Key::Alt, Key::ShiftLeft]);
+        assert!(check_switch_key(
+            &keys,
+            &modifiers,
+            &Key::ShiftLeft,
+            "alt shift"
+        ));
+        assert!(check_switch_key(
+            &keys,
+            &modifiers,
+            &Key::ShiftLeft,
+            "shift alt"
+        ));
+        assert!(!check_switch_key(
+            &keys,
+            &modifiers,
+            &Key::ShiftLeft,
+            "ctrl shift"
+        ));
+
+        let (keys, modifiers) = press(&[Key::Alt, Key::ShiftLeft, Key::KeyA]);
+        assert!(!check_switch_key(
+            &keys,
+            &modifiers,
+            &Key::KeyA,
+            "alt shift"
+        ));
+    }
+
+    #[test]
+    fn switch_key_with_regular_key() {
+        let (keys, modifiers) = press(&[Key::MetaLeft, Key::Space]);
+        assert!(check_switch_key(
+            &keys,
+            &modifiers,
+            &Key::Space,
+            "super space"
+        ));
+        assert!(!check_switch_key(
+            &keys,
+            &modifiers,
+            &Key::Space,
+            "alt space"
+        ));
+    }
+
+    #[test]
+    fn auto_repeat_does_not_retrigger() {
+        enum Event {
+            Press(Key),
+            Release(Key),
+        }
+        use Event::*;
+
+        let events = [
+            Press(Key::ControlLeft),
+            Press(Key::ShiftLeft),
+            Press(Key::KeyQ),
+            // held: the keyboard repeats the last key, and the modifier too
+            Press(Key::KeyQ),
+            Press(Key::KeyQ),
+            Press(Key::ShiftLeft),
+            Press(Key::KeyQ),
+            Release(Key::KeyQ),
+            Press(Key::KeyQ),
+            Release(Key::KeyQ),
+            Release(Key::ShiftLeft),
+            Release(Key::ControlLeft),
+        ];
+
+        let mut keys = KeyTracker::default();
+        let mut fresh_presses = Vec::new();
+        let mut triggered = 0;
+        for event in events {
+            match event {
+                Press(key) => {
+                    let fresh = keys.press(key);
+                    fresh_presses.push(fresh);
+                    if fresh && check_hotkey(&keys.pressed_keys, &keys.modifiers, "ctrl shift q") {
+                        triggered += 1;
+                    }
+                }
+                Release(key) => keys.release(key),
+            }
+        }
+
+        assert_eq!(
+            fresh_presses,
+            [true, true, true, false, false, false, false, true]
+        );
+        assert_eq!(triggered, 2);
+        assert!(keys.pressed_keys.is_empty());
+        assert!(!keys.modifiers.any());
+    }
+
+    #[test]
+    fn key_names_round_trip() {
+        for &(name, key) in KEY_NAMES {
+            assert_eq!(str_to_key(name), Some(key));
+            assert_eq!(key_to_str(&key).and_then(str_to_key), Some(key));
+        }
+        assert_eq!(str_to_key("F12"), Some(Key::F12));
+        assert_eq!(key_to_str(&Key::ShiftRight), Some("shift"));
+    }
+
+    #[test]
+    fn side_specific_modifiers() {
+        let (keys, modifiers) = press(&[Key::ShiftRight, Key::KeyQ]);
+        assert!(check_hotkey(&keys, &modifiers, "lshift q"));
+        assert_eq!(
+            combo_keys("Alt rshift"),
+            Some(vec![Key::Alt, Key::ShiftRight])
+        );
+        assert_eq!(
+            combo_keys("super space"),
+            Some(vec![Key::MetaLeft, Key::Space])
+        );
+        assert_eq!(combo_keys("alt nosuchkey"), None);
+    }
+
+    #[test]
+    fn hotkey_action_names() {
+        assert_eq!(
+            HotkeyAction::from_name("add_window"),
+            Some(HotkeyAction::AddWindow)
+        );
+        assert_eq!(
+            HotkeyAction::from_name("remove_window"),
+            Some(HotkeyAction::RemoveWindow)
+        );
+        assert_eq!(
+            HotkeyAction::from_name("set_layout:2"),
+            Some(HotkeyAction::SetLayout(2))
+        );
+        assert_eq!(HotkeyAction::from_name("set_layout:x"), None);
+        assert_eq!(HotkeyAction::from_name("toggle"), None);
+    }
+}
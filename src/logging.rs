@@ -0,0 +1,181 @@
+use crate::config::{AppConfig, LogLevel, LogMode, LogTarget};
+use anyhow::{anyhow, Context, Result};
+use simplelog::{Config as LogConfig, LevelFilter, WriteLogger};
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use syslog::Facility;
+
+/// Overrides `log_level`, e.g. `NSKBD_LOG=debug`
+const LOG_LEVEL_VAR: &str = "NSKBD_LOG";
+/// Overrides `log_path`
+const LOG_PATH_VAR: &str = "NSKBD_LOG_FILE";
+
+/// Only the service owns the log file. One-shot commands write to the same
+/// file while the service may have it open, so they always append and never
+/// truncate or rotate it.
+pub fn init_logger(config: &AppConfig, log_path: &Path, owns_log: bool) -> Result<()> {
+    let level = log_level(config);
+
+    match config.log_target {
+        LogTarget::File => {
+            let mode = if owns_log {
+                config.log_mode
+            } else {
+                LogMode::Append
+            };
+            let log_file = open_log_file(mode, log_path)
+                .context(format!("Failed to create log file: {}", log_path.display()))?;
+
+            let writer: Box<dyn Write + Send> = match config.log_max_bytes {
+                Some(max_bytes) if owns_log => {
+                    Box::new(RotatingFile::new(log_path, log_file, max_bytes)?)
+                }
+                _ => Box::new(log_file),
+            };
+
+            WriteLogger::init(level, LogConfig::default(), writer)
+                .context("Failed to initialize logger")
+        }
+        LogTarget::Stderr => WriteLogger::init(level, LogConfig::default(), io::stderr())
+            .context("Failed to initialize logger"),
+        LogTarget::Syslog => syslog::init_unix(Facility::LOG_USER, level)
+            .map_err(|e| anyhow!("Failed to initialize syslog logger: {}", e)),
+    }
+}
+
+/// `NSKBD_LOG_FILE` wins over `log_path`. Relative paths are taken from the
+/// current directory, like the default one.
+pub fn log_path(config: &AppConfig, current_dir: &Path, default: &str) -> PathBuf {
+    let path = env::var_os(LOG_PATH_VAR)
+        .map(PathBuf::from)
+        .or_else(|| config.log_path.clone())
+        .unwrap_or_else(|| PathBuf::from(default));
+    current_dir.join(path)
+}
+
+fn log_level(config: &AppConfig) -> LevelFilter {
+    if let Ok(value) = env::var(LOG_LEVEL_VAR) {
+        match value.parse() {
+            Ok(level) => return level,
+            Err(_) => eprintln!(
+                "Warning: unknown log level in {}: \"{}\", using log_level from the config",
+                LOG_LEVEL_VAR, value
+            ),
+        }
+    }
+
+    match config.log_level {
+        LogLevel::Off => LevelFilter::Off,
+        LogLevel::Error => LevelFilter::Error,
+        LogLevel::Warn => LevelFilter::Warn,
+        LogLevel::Info => LevelFilter::Info,
+        LogLevel::Debug => LevelFilter::Debug,
+        LogLevel::Trace => LevelFilter::Trace,
+    }
+}
+
+fn open_log_file(mode: LogMode, log_path: &Path) -> io::Result<File> {
+    match mode {
+        LogMode::Truncate => {
+            if log_path.exists() {
+                fs::remove_file(log_path).ok();
+            }
+            File::create(log_path)
+        }
+        LogMode::Append => OpenOptions::new().create(true).append(true).open(log_path),
+        LogMode::Rotate => {
+            if log_path.exists() {
+                let timestamp = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|elapsed| elapsed.as_secs())
+                    .unwrap_or_default();
+                let mut rotated = log_path.as_os_str().to_owned();
+                rotated.push(format!(".{}", timestamp));
+                fs::rename(log_path, rotated)?;
+            }
+            File::create(log_path)
+        }
+    }
+}
+
+/// Log file that is moved to `<log>.1` once it grows past `max_bytes`, so a
+/// long running daemon does not fill the disk. Rotation only happens between
+/// lines, since the logger writes a record in several pieces.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    written: u64,
+    max_bytes: u64,
+    at_line_start: bool,
+}
+
+impl RotatingFile {
+    fn new(path: &Path, file: File, max_bytes: u64) -> io::Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            written: file.metadata()?.len(),
+            file,
+            max_bytes,
+            at_line_start: true,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let mut rotated = self.path.as_os_str().to_owned();
+        rotated.push(".1");
+        fs::rename(&self.path, rotated)?;
+        self.file = File::create(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.at_line_start && self.written > 0 && self.written >= self.max_bytes {
+            self.rotate()?;
+        }
+
+        let written = self.file.write(buf)?;
+        self.written += written as u64;
+        if written > 0 {
+            self.at_line_start = buf[written - 1] == b'\n';
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotates_between_lines() {
+        let dir = env::temp_dir().join(format!("nskbd-test-{}-rotation", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("test.log");
+
+        let file = File::create(&path).unwrap();
+        let mut log = RotatingFile::new(&path, file, 10).unwrap();
+        log.write_all(b"first ").unwrap();
+        log.write_all(b"record\n").unwrap();
+        log.write_all(b"second\n").unwrap();
+        log.flush().unwrap();
+
+        let rotated = fs::read_to_string(dir.join("test.log.1")).unwrap();
+        assert_eq!(rotated, "first record\n");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second\n");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,1226 @@
+use crate::{
+    backend::{self, BackendEvent, LayoutBackend},
+    config::{AppConfig, LayoutRef, StatusFormat},
+    dbus,
+    error::FatalError,
+    hotkey::{self, HotkeyAction, InputState, KeyTracker},
+    logging,
+    window::WindowClass,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use log::{debug, error, info, warn};
+use rdev::{listen, Event as KbdEvent, EventType};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    env, fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const EVENT_POLL_TIMEOUT: Duration = Duration::from_millis(500);
+const SWITCH_KEY_SETTLE: Duration = Duration::from_millis(50);
+
+/// Layout state shared with the keyboard listener, which updates it when the
+/// user switches the layout with their own key.
+#[derive(Debug, Default)]
+struct LayoutCache {
+    last_window_id: Option<u32>,
+    current_group: Option<u8>,
+    /// Keyed by X window IDs, which the server reuses, so closed windows are
+    /// dropped from here
+    window_layouts: HashMap<u32, u8>,
+    /// What the focused window's class or title mapped to when it was last
+    /// evaluated
+    mapped_layout: Option<LayoutRef>,
+}
+
+pub struct KeyboardLayoutSwitcher {
+    config_path: PathBuf,
+    log_path: PathBuf,
+    config: Arc<Mutex<AppConfig>>,
+    reevaluate: Arc<AtomicBool>,
+    shutdown: Arc<AtomicBool>,
+    input: Arc<InputState>,
+    cache: Arc<Mutex<LayoutCache>>,
+    temporary_layout: Arc<Mutex<Option<(String, String)>>>,
+    backend: Arc<dyn LayoutBackend>,
+}
+
+impl KeyboardLayoutSwitcher {
+    /// Sets up the service, which owns the log file.
+    pub fn new(config_file: &str, log_file: &str) -> Result<Self> {
+        Self::open(config_file, log_file, true)
+    }
+
+    /// Sets up a one-shot command that may run next to the service.
+    pub fn for_command(config_file: &str, log_file: &str) -> Result<Self> {
+        Self::open(config_file, log_file, false)
+    }
+
+    fn open(config_file: &str, log_file: &str, owns_log: bool) -> Result<Self> {
+        let current_dir = env::current_dir().context("Failed to get current directory")?;
+        let config_path = current_dir.join(config_file);
+
+        let mut config = AppConfig::load_from_file(&config_path).context(FatalError::Config)?;
+        let log_path = logging::log_path(&config, &current_dir, log_file);
+        logging::init_logger(&config, &log_path, owns_log).context(FatalError::Logger)?;
+        config.log_version(&config_path);
+        config.compile_title_patterns();
+
+        let backend = backend::connect(&config)?;
+
+        Ok(Self {
+            config_path,
+            log_path,
+            config: Arc::new(Mutex::new(config)),
+            reevaluate: Arc::new(AtomicBool::new(false)),
+            shutdown: Arc::new(AtomicBool::new(false)),
+            input: Arc::new(InputState::default()),
+            cache: Arc::new(Mutex::new(LayoutCache::default())),
+            temporary_layout: Arc::new(Mutex::new(None)),
+            backend: Arc::from(backend),
+        })
+    }
+
+    fn get_current_layout(&self) -> Option<u8> {
+        self.backend
+            .current_layout()
+            .map_err(|e| {
+                error!("Failed to get current layout: {}", e);
+                e
+            })
+            .ok()
+    }
+
+    /// Prints the current layout without the daemon setup: no logger, no log
+    /// file and no config creation, since status bars call this very often.
+    pub fn print_status_line(config_file: &str, format: Option<StatusFormat>) -> Result<()> {
+        let config_path = env::current_dir()
+            .context("Failed to get current directory")?
+            .join(config_file);
+        let config = AppConfig::read_file(&config_path).unwrap_or_default();
+        let format = format.unwrap_or(config.status_line_format);
+
+        let backend = backend::connect_query(&config)?;
+        println!("{}", Self::status_line(backend.as_ref(), format)?);
+        Ok(())
+    }
+
+    /// `--keycodes`: rdev names the keys, the keyboard mapping gives their
+    /// X11 keycodes.
+    pub fn print_key_events(config_file: &str) -> Result<()> {
+        let config_path = env::current_dir()
+            .context("Failed to get current directory")?
+            .join(config_file);
+        let config = AppConfig::read_file(&config_path).unwrap_or_default();
+
+        let backend = backend::connect_query(&config)?;
+        hotkey::print_key_events(move |key| backend.keycode_of(key).ok().flatten())
+    }
+
+    fn status_line(backend: &dyn LayoutBackend, format: StatusFormat) -> Result<String> {
+        let layout = backend.current_layout()?;
+        let index = usize::from(layout);
+
+        let name = match format {
+            StatusFormat::Index => None,
+            StatusFormat::Short => backend
+                .layout_symbols()?
+                .get(index)
+                .map(|name| name.to_uppercase()),
+            StatusFormat::Full => backend.layout_names()?.get(index).cloned(),
+        };
+
+        Ok(name.unwrap_or_else(|| layout.to_string()))
+    }
+
+    /// Renders the configured mappings as a shell script that applies each
+    /// layout through `--set-layout`, for reproducing decisions by hand.
+    pub fn export_script(&self) -> Result<String> {
+        let config = self
+            .config
+            .lock()
+            .map_err(|e| anyhow!("Config lock error: {}", e))?
+            .clone();
+
+        let executable = env::current_exe().context("Failed to get executable path")?;
+        let describe = |layout: &LayoutRef| match layout {
+            LayoutRef::Variant { .. } => format!("{} (added to the keymap on demand)", layout),
+            _ => match self.resolve_layout(layout) {
+                Some(group) => format!("{} (group {})", layout, group),
+                None => format!("{} (unknown layout)", layout),
+            },
+        };
+
+        let mut script = String::from("#!/bin/sh\n");
+        script.push_str("# Generated by NSKeyboardLayoutSwitcher --export-script\n");
+        // Assignments are not split, so the default can be quoted on its own
+        script.push_str(&format!(
+            "NSKBD=${{NSKBD:-{}}}\n",
+            shell_quote(&executable.to_string_lossy())
+        ));
+
+        let quote = |layout: &LayoutRef| shell_quote(&layout.to_string());
+        let classes: BTreeMap<_, _> = config.window_layout_map.iter().collect();
+
+        for (class, layout) in classes {
+            script.push('\n');
+            match AppConfig::glob_source(class) {
+                Some(pattern) => script.push_str(&format!(
+                    "# windows with class matching {} -> {}\n",
+                    pattern,
+                    describe(layout)
+                )),
+                None => script.push_str(&format!("# {} -> {}\n", class, describe(layout))),
+            }
+            if let Some(device_id) = config.window_device_map.get(class) {
+                script.push_str(&format!(
+                    "# applied to XKB device {}, --set-layout uses the default device\n",
+                    device_id
+                ));
+            }
+            script.push_str(&format!("\"$NSKBD\" --set-layout {}\n", quote(layout)));
+        }
+
+        let titles: BTreeMap<_, _> = config.window_title_map.iter().collect();
+
+        for (title, layout) in titles {
+            script.push('\n');
+            script.push_str(&format!(
+                "# windows with title matching /{}/ -> {}\n",
+                title,
+                describe(layout)
+            ));
+            script.push_str(&format!("\"$NSKBD\" --set-layout {}\n", quote(layout)));
+        }
+
+        Ok(script)
+    }
+
+    pub fn add_current_window(&self) -> Result<()> {
+        let window_id = self
+            .backend
+            .active_window()
+            .context("Failed to get window ID")?;
+
+        let window_class = self
+            .backend
+            .window_class_pair(window_id)
+            .context("Failed to detect window class")?;
+
+        self.add_window_guard(window_id, &window_class)?;
+
+        let layout = self
+            .get_current_layout()
+            .context("Failed to detect current layout")?;
+        let layout = self.layout_ref_for(layout);
+
+        let mut config = self
+            .config
+            .lock()
+            .map_err(|e| anyhow!("Config lock error: {}", e))?;
+
+        // New mappings use the bare class, an existing `instance.class` entry
+        // for this window is updated instead of being shadowed
+        let key = config
+            .exact_key(&window_class)
+            .map(str::to_string)
+            .unwrap_or(window_class.class);
+        config.set_mapping(key, layout);
+        config.save_to_file(&self.config_path)?;
+
+        // The focused window is still `last_window_id`, so without this the
+        // new mapping would only apply after focus moves away and back
+        self.reevaluate.store(true, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    /// Refuses windows that are not normal application windows, so hitting the
+    /// add hotkey while a dock or dialog has focus does not pollute the config.
+    fn add_window_guard(&self, window_id: u32, window_class: &WindowClass) -> Result<()> {
+        let window_types = self.backend.window_types(window_id);
+        let config = self
+            .config
+            .lock()
+            .map_err(|e| anyhow!("Config lock error: {}", e))?;
+
+        if !config.allows_window_types(&window_types) {
+            bail!(
+                "Window {} ({}) has type {}, allowed types are {}",
+                window_id,
+                window_class,
+                window_types.join(", "),
+                config.add_window_types.join(", ")
+            );
+        }
+
+        Ok(())
+    }
+
+    pub fn remove_current_window(&self) -> Result<()> {
+        let window_id = self
+            .backend
+            .active_window()
+            .context("Failed to get window ID")?;
+
+        let window_class = self
+            .backend
+            .window_class_pair(window_id)
+            .context("Failed to detect window class")?;
+
+        let mut config = self
+            .config
+            .lock()
+            .map_err(|e| anyhow!("Config lock error: {}", e))?;
+
+        let Some(key) = config.exact_key(&window_class).map(str::to_string) else {
+            info!(
+                "Window class {} is not mapped, nothing to remove",
+                window_class
+            );
+            return Ok(());
+        };
+
+        config.remove_mapping(&key)?;
+        config.save_to_file(&self.config_path)?;
+
+        Ok(())
+    }
+
+    fn run_hotkey_action(&self, action: HotkeyAction) {
+        match action {
+            HotkeyAction::AddWindow => {
+                if let Err(e) = self.add_current_window() {
+                    error!("Failed to add window: {}", e);
+                }
+            }
+            HotkeyAction::RemoveWindow => {
+                if let Err(e) = self.remove_current_window() {
+                    error!("Failed to remove window: {}", e);
+                }
+            }
+            HotkeyAction::SetLayout(group) => {
+                if let Err(e) = self.switch_to_group(group) {
+                    error!("Failed to switch layout: {}", e);
+                }
+            }
+        }
+    }
+
+    /// `switch_layout` for a group index coming from outside, checked against
+    /// the layouts XKB actually has.
+    pub fn switch_to_group(&self, group: u8) -> Result<()> {
+        let count = self
+            .backend
+            .group_count()
+            .context("Failed to get layout count")?;
+        if group >= count {
+            bail!(
+                "Layout {} is out of range, only {} layouts are configured",
+                group,
+                count
+            );
+        }
+        self.switch_layout(group)
+    }
+
+    /// Class mappings with their resolved group. Layouts that are only added
+    /// to the keymap on demand have no group yet and are left out.
+    pub fn mappings(&self) -> Result<Vec<(String, u8)>> {
+        let config = self
+            .config
+            .lock()
+            .map_err(|e| anyhow!("Config lock error: {}", e))?
+            .clone();
+
+        let mut mappings: Vec<(String, u8)> = config
+            .window_layout_map
+            .iter()
+            .filter(|(_, layout)| !matches!(layout, LayoutRef::Variant { .. }))
+            .filter_map(|(class, layout)| Some((class.clone(), self.resolve_layout(layout)?)))
+            .collect();
+        mappings.sort();
+        Ok(mappings)
+    }
+
+    pub fn resolve_layout(&self, layout: &LayoutRef) -> Option<u8> {
+        match layout {
+            LayoutRef::Index(index) => Some(*index),
+            LayoutRef::Name(name) => {
+                let group = self.backend.resolve_layout_name(name);
+                if group.is_none() {
+                    error!("Unknown layout name: {}", name);
+                }
+                group
+            }
+            LayoutRef::Variant { layout, variant } => self
+                .ensure_layout_group(layout, variant.as_deref().unwrap_or_default())
+                .map_err(|e| error!("Failed to load layout {}: {}", layout, e))
+                .ok(),
+        }
+    }
+
+    /// Returns the group of the given layout, appending it to the keymap as a
+    /// temporary last group if it is not loaded. Only one temporary group is
+    /// kept and it is always last, so the indices of the other groups never
+    /// shift.
+    fn ensure_layout_group(&self, layout: &str, variant: &str) -> Result<u8> {
+        let mut layouts = self.backend.keymap_layouts()?;
+        if let Some(index) = layouts
+            .iter()
+            .position(|(l, v)| l.eq_ignore_ascii_case(layout) && v.eq_ignore_ascii_case(variant))
+        {
+            return Ok(u8::try_from(index)?);
+        }
+
+        let mut temporary = self
+            .temporary_layout
+            .lock()
+            .map_err(|e| anyhow!("Temporary layout lock error: {}", e))?;
+
+        if temporary.is_some() && layouts.last() == temporary.as_ref() {
+            layouts.pop();
+        }
+        self.forget_groups_from(layouts.len());
+        layouts.push((layout.to_string(), variant.to_string()));
+        self.backend.load_keymap_layouts(&layouts)?;
+        info!("Loaded temporary layout {}({})", layout, variant);
+
+        *temporary = layouts.last().cloned();
+        Ok(u8::try_from(layouts.len() - 1)?)
+    }
+
+    /// Drops the temporary group added by `ensure_layout_group`, if any.
+    /// Returns whether the keymap was changed.
+    fn release_temporary_layout(&self) -> bool {
+        let mut temporary = match self.temporary_layout.lock() {
+            Ok(temporary) => temporary,
+            Err(e) => {
+                error!("Temporary layout lock error: {}", e);
+                return false;
+            }
+        };
+
+        let Some(added) = temporary.take() else {
+            return false;
+        };
+
+        let result = self.backend.keymap_layouts().and_then(|mut layouts| {
+            if layouts.last() == Some(&added) {
+                layouts.pop();
+                self.forget_groups_from(layouts.len());
+                self.backend.load_keymap_layouts(&layouts)?;
+            }
+            Ok(())
+        });
+
+        match result {
+            Ok(()) => {
+                info!("Removed temporary layout {}({})", added.0, added.1);
+                true
+            }
+            Err(e) => {
+                error!("Failed to remove temporary layout: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Remembered groups at or past `count` belong to a temporary group that
+    /// is being replaced or removed, so they no longer mean the same layout.
+    fn forget_groups_from(&self, count: usize) {
+        self.cache()
+            .window_layouts
+            .retain(|_, group| usize::from(*group) < count);
+    }
+
+    /// Drops the temporary group unless the next layout is one itself.
+    /// Reloading the keymap may move the locked group, so the cached group can
+    /// no longer be trusted either way.
+    fn prepare_keymap_for(&self, layout: Option<&LayoutRef>) {
+        let is_variant = matches!(layout, Some(LayoutRef::Variant { .. }));
+        if is_variant || self.release_temporary_layout() {
+            self.cache().current_group = None;
+        }
+    }
+
+    /// Prefers the layout name, so saved mappings survive layout reordering.
+    /// Falls back to the index when the name is unknown or ambiguous.
+    fn layout_ref_for(&self, group: u8) -> LayoutRef {
+        self.backend
+            .layout_symbols()
+            .ok()
+            .and_then(|symbols| symbols.get(usize::from(group)).cloned())
+            .filter(|name| {
+                !name.is_empty() && self.backend.resolve_layout_name(name) == Some(group)
+            })
+            .map(LayoutRef::Name)
+            .unwrap_or(LayoutRef::Index(group))
+    }
+
+    pub fn switch_layout(&self, layout: u8) -> Result<()> {
+        let error = match self.backend.set_layout(layout) {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+
+        let switch_keys = {
+            let config = self
+                .config
+                .lock()
+                .map_err(|e| anyhow!("Config lock error: {}", e))?;
+            match config.xtest_keys() {
+                Some(keys) if config.xtest_fallback => keys,
+                _ => return Err(error).context("Failed to switch layout"),
+            }
+        };
+
+        warn!(
+            "XKB layout switch failed ({}), falling back to XTEST",
+            error
+        );
+        self.backend
+            .set_layout_with_keys(layout, &switch_keys)
+            .context("Failed to switch layout with XTEST")
+    }
+
+    fn start_keyboard_listener(&self) -> Result<()> {
+        let config = Arc::clone(&self.config);
+        let input = Arc::clone(&self.input);
+        let shutdown = Arc::clone(&self.shutdown);
+        let switcher = self.clone();
+
+        thread::spawn(move || {
+            let mut keys = KeyTracker::default();
+            let mut last_triggered: HashMap<HotkeyAction, Instant> = HashMap::new();
+
+            // rdev offers no way to stop `listen`, so after shutdown the thread
+            // just ignores events until the process exits
+            let callback = move |event: KbdEvent| match event.event_type {
+                _ if shutdown.load(Ordering::SeqCst) => {}
+                EventType::KeyPress(key) => {
+                    let fresh = keys.press(key);
+                    input.publish(&keys.modifiers, &keys.pressed_keys);
+
+                    // A held combo must not fire again once the debounce
+                    // window has passed
+                    if !fresh {
+                        return;
+                    }
+
+                    let KeyTracker {
+                        pressed_keys,
+                        modifiers,
+                    } = &keys;
+
+                    let (action, debounce, switch_key) = {
+                        let config = match config.lock() {
+                            Ok(c) => c,
+                            Err(e) => {
+                                error!("Config lock error: {}", e);
+                                return;
+                            }
+                        };
+                        let action = config
+                            .hotkeys
+                            .iter()
+                            .filter(|(_, hotkey)| {
+                                hotkey::check_hotkey(pressed_keys, modifiers, hotkey)
+                            })
+                            .find_map(|(name, _)| {
+                                let action = HotkeyAction::from_name(name);
+                                if action.is_none() {
+                                    error!("Unknown hotkey action: {}", name);
+                                }
+                                action
+                            });
+                        let switch_key = config.layout_switch_keys.iter().any(|combo| {
+                            hotkey::check_switch_key(pressed_keys, modifiers, &key, combo)
+                        });
+                        (
+                            action,
+                            Duration::from_millis(config.hotkey_debounce_ms),
+                            switch_key,
+                        )
+                    };
+
+                    // Plain typing is never logged, only attempts at a hotkey
+                    match action {
+                        Some(action) => {
+                            debug!("Hotkey {:?} matched {:?} with {:?}", action, key, modifiers)
+                        }
+                        None if modifiers.any() => {
+                            debug!("No hotkey matched {:?} with {:?}", key, modifiers)
+                        }
+                        None => {}
+                    }
+
+                    if switch_key {
+                        debug!("Layout switch key pressed");
+                        let switcher_clone = switcher.clone();
+                        thread::spawn(move || switcher_clone.handle_switch_key());
+                    }
+
+                    if let Some(action) = action {
+                        let now = Instant::now();
+                        let debounced = !debounce.is_zero()
+                            && last_triggered
+                                .get(&action)
+                                .is_some_and(|last| now.duration_since(*last) <= debounce);
+
+                        if debounced {
+                            debug!("Hotkey {:?} ignored, triggered too recently", action);
+                        } else {
+                            last_triggered.insert(action, now);
+                            let switcher_clone = switcher.clone();
+                            thread::spawn(move || switcher_clone.run_hotkey_action(action));
+                        }
+                    }
+                }
+                EventType::KeyRelease(key) => {
+                    keys.release(key);
+                    input.publish(&keys.modifiers, &keys.pressed_keys);
+                }
+                _ => {}
+            };
+
+            if let Err(e) = listen(callback) {
+                error!("Keyboard listener error: {:?}", e);
+            }
+        });
+
+        Ok(())
+    }
+
+    fn start_config_watcher(&self) {
+        let config = Arc::clone(&self.config);
+        let config_path = self.config_path.clone();
+        let reevaluate = Arc::clone(&self.reevaluate);
+        let shutdown = Arc::clone(&self.shutdown);
+
+        thread::spawn(move || {
+            let modified_time = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+            let mut last_modified = modified_time(&config_path);
+
+            while !shutdown.load(Ordering::SeqCst) {
+                thread::sleep(CONFIG_POLL_INTERVAL);
+
+                let modified = modified_time(&config_path);
+                if modified.is_none() || modified == last_modified {
+                    continue;
+                }
+                last_modified = modified;
+
+                match AppConfig::load_existing(&config_path) {
+                    Ok(mut new_config) => match config.lock() {
+                        Ok(mut config) => {
+                            new_config.compile_title_patterns();
+                            new_config.log_version(&config_path);
+                            *config = new_config;
+                            reevaluate.store(true, Ordering::SeqCst);
+                            info!("Config reloaded from {}", config_path.display());
+                        }
+                        Err(e) => error!("Config lock error: {}", e),
+                    },
+                    Err(e) => error!("Failed to reload config, keeping previous one: {:#}", e),
+                }
+            }
+        });
+    }
+
+    fn handle_window_change(&mut self, window_id: u32) -> Result<()> {
+        if !self.reevaluate.load(Ordering::SeqCst) && self.cache().last_window_id == Some(window_id)
+        {
+            return Ok(());
+        }
+
+        let window_class = self.backend.window_class_pair(window_id);
+        let (remember_per_window, is_splash) = {
+            let config = self
+                .config
+                .lock()
+                .map_err(|e| anyhow!("Config lock error: {}", e))?;
+            let is_splash = window_class
+                .as_ref()
+                .is_some_and(|class| config.is_splash_class(class));
+            (config.remember_per_window, is_splash)
+        };
+
+        // Splash windows are transient, so the real main window that follows
+        // decides the layout instead
+        if is_splash {
+            info!("Ignoring focus change to splash window {}", window_id);
+            return Ok(());
+        }
+
+        self.reevaluate.store(false, Ordering::SeqCst);
+
+        let modifiers = self.input.modifiers();
+        if modifiers.any() {
+            debug!(
+                "Focus changed to window {} with {:?} and keys {:?} held",
+                window_id,
+                modifiers,
+                self.input.pressed_keys()
+            );
+        }
+
+        if remember_per_window {
+            self.remember_current_layout();
+        }
+
+        self.cache().last_window_id = Some(window_id);
+
+        // Resolving may run setxkbmap, so the mapping is copied out first
+        // rather than holding the lock the keyboard listener needs
+        let target = {
+            let config = self
+                .config
+                .lock()
+                .map_err(|e| anyhow!("Config lock error: {}", e))?;
+            let mapping =
+                config.window_mapping(self.backend.as_ref(), window_id, window_class.as_ref());
+            self.cache().mapped_layout = mapping.as_ref().map(|(layout, _)| layout.clone());
+            mapping.or_else(|| {
+                config
+                    .remember_per_window
+                    .then(|| self.cache().window_layouts.get(&window_id).copied())
+                    .flatten()
+                    .map(LayoutRef::Index)
+                    .or_else(|| config.default_layout.clone())
+                    .map(|layout| (layout, None))
+            })
+        };
+
+        self.prepare_keymap_for(target.as_ref().map(|(layout, _)| layout));
+        let target =
+            target.and_then(|(layout, device_id)| Some((self.resolve_layout(&layout)?, device_id)));
+
+        let result = match target {
+            Some((group, Some(device_id))) => self.backend.set_layout_on(device_id, group),
+            Some((group, None)) => self.apply_layout(group),
+            None => Ok(()),
+        };
+
+        if let Err(e) = result {
+            error!("Failed to switch layout: {}", e);
+        }
+
+        Ok(())
+    }
+
+    /// Browsers and terminals retitle the focused window all the time. Only a
+    /// change in what the window maps to counts, so a manual switch is not
+    /// undone on every retitle.
+    fn handle_title_change(&mut self, window_id: u32) -> Result<()> {
+        if self.cache().last_window_id != Some(window_id) {
+            return Ok(());
+        }
+
+        let window_class = self.backend.window_class_pair(window_id);
+        let mapping = self
+            .config
+            .lock()
+            .map_err(|e| anyhow!("Config lock error: {}", e))?
+            .window_mapping(self.backend.as_ref(), window_id, window_class.as_ref())
+            .map(|(layout, _)| layout);
+
+        if mapping == self.cache().mapped_layout {
+            return Ok(());
+        }
+
+        debug!("Title change of window {} changed its mapping", window_id);
+        self.reevaluate.store(true, Ordering::SeqCst);
+        self.handle_window_change(window_id)
+    }
+
+    fn handle_no_window(&mut self) -> Result<()> {
+        if self.cache().last_window_id.is_none() {
+            return Ok(());
+        }
+
+        let config = self
+            .config
+            .lock()
+            .map_err(|e| anyhow!("Config lock error: {}", e))?
+            .clone();
+
+        if config.remember_per_window {
+            self.remember_current_layout();
+        }
+
+        self.cache().last_window_id = None;
+
+        self.prepare_keymap_for(config.no_window_layout.as_ref());
+        if let Some(layout) = config
+            .no_window_layout
+            .and_then(|layout| self.resolve_layout(&layout))
+        {
+            if let Err(e) = self.apply_layout(layout) {
+                error!("Failed to switch layout: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Forgets remembered layouts of windows that were closed. Window managers
+    /// without `_NET_CLIENT_LIST` report no windows, then nothing is dropped.
+    fn handle_client_list_change(&self) {
+        if self.cache().window_layouts.is_empty() {
+            return;
+        }
+
+        let clients: HashSet<u32> = self.backend.client_windows().into_iter().collect();
+        if clients.is_empty() {
+            return;
+        }
+
+        self.cache()
+            .window_layouts
+            .retain(|window_id, _| clients.contains(window_id));
+    }
+
+    /// Like `switch_layout`, but skips the X request when the observed group
+    /// already matches.
+    fn apply_layout(&self, group: u8) -> Result<()> {
+        if self.cache().current_group == Some(group) {
+            debug!("Layout {} is already active", group);
+            return Ok(());
+        }
+        self.switch_layout(group)
+    }
+
+    fn handle_group_change(&self, group: u8) -> Result<()> {
+        debug!("XKB group changed to {}", group);
+
+        let remember_per_window = self
+            .config
+            .lock()
+            .map_err(|e| anyhow!("Config lock error: {}", e))?
+            .remember_per_window;
+
+        let mut cache = self.cache();
+        cache.current_group = Some(group);
+        if let (true, Some(window_id)) = (remember_per_window, cache.last_window_id) {
+            cache.window_layouts.insert(window_id, group);
+        }
+
+        Ok(())
+    }
+
+    /// Runs when the user presses their own layout switch key. The cached
+    /// group is dropped right away so a focus change in between does not
+    /// trust it, then read back once XKB has applied the switch.
+    fn handle_switch_key(&self) {
+        self.cache().current_group = None;
+        thread::sleep(SWITCH_KEY_SETTLE);
+
+        match self.backend.current_layout() {
+            Ok(group) => {
+                if let Err(e) = self.handle_group_change(group) {
+                    error!("Failed to record layout switch: {}", e);
+                }
+            }
+            Err(e) => error!("Failed to get current layout: {}", e),
+        }
+    }
+
+    fn remember_current_layout(&self) {
+        let layout = self.get_current_layout();
+        let mut cache = self.cache();
+        if let (Some(window_id), Some(layout)) = (cache.last_window_id, layout) {
+            cache.window_layouts.insert(window_id, layout);
+        }
+    }
+
+    /// The cache only holds plain values, so a poisoned lock is still usable.
+    fn cache(&self) -> MutexGuard<'_, LayoutCache> {
+        self.cache.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn check_group_count(&self) -> Result<()> {
+        let group_count = self.backend.group_count()?;
+        if group_count > 1 {
+            return Ok(());
+        }
+
+        let message = format!(
+            "Only {} keyboard layout is configured in XKB, so there is nothing to switch. \
+             Configure at least two layouts, e.g. `setxkbmap -layout us,ru`",
+            group_count
+        );
+
+        let require_multiple_layouts = self
+            .config
+            .lock()
+            .map_err(|e| anyhow!("Config lock error: {}", e))?
+            .require_multiple_layouts;
+
+        if require_multiple_layouts {
+            return Err(anyhow!(message).context(FatalError::SingleLayout));
+        }
+
+        error!("{}", message);
+        eprintln!("Warning: {}", message);
+        Ok(())
+    }
+
+    pub fn run(&mut self) -> Result<()> {
+        self.check_group_count()?;
+        self.start_keyboard_listener()?;
+        self.start_config_watcher();
+        dbus::start_service(self.clone(), Arc::clone(&self.shutdown));
+
+        for signal in [SIGINT, SIGTERM] {
+            signal_hook::flag::register(signal, Arc::clone(&self.shutdown))
+                .context("Failed to install signal handler")?;
+        }
+
+        self.backend.watch()?;
+        let group = self.get_current_layout();
+        self.cache().current_group = group;
+
+        if let Some(win) = self.backend.active_window() {
+            self.handle_window_change(win)?;
+        }
+
+        while !self.shutdown.load(Ordering::SeqCst) {
+            match self.backend.next_event(EVENT_POLL_TIMEOUT) {
+                Ok(Some(event)) => self.handle_event(event)?,
+                Ok(None) => self.reevaluate_active_window()?,
+                Err(e) => {
+                    error!("Layout backend error: {}", e);
+                    thread::sleep(Duration::from_millis(100));
+                }
+            }
+        }
+
+        // The temporary group is only meant to live while its window has
+        // focus, so it must not outlive the service either
+        self.release_temporary_layout();
+        info!("Shutting down");
+        Ok(())
+    }
+
+    /// Mapping changes made outside the event loop, such as adding a window or
+    /// reloading the config, only set `reevaluate`. The focused window is
+    /// checked again here rather than on the next focus change.
+    fn reevaluate_active_window(&mut self) -> Result<()> {
+        if !self.reevaluate.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        match self.backend.active_window() {
+            Some(win) => self.handle_window_change(win),
+            None => Ok(()),
+        }
+    }
+
+    fn handle_event(&mut self, event: BackendEvent) -> Result<()> {
+        match event {
+            BackendEvent::FocusChanged(Some(win)) => self.handle_window_change(win),
+            BackendEvent::FocusChanged(None) => self.handle_no_window(),
+            BackendEvent::GroupChanged(group) => self.handle_group_change(group),
+            BackendEvent::Retitled(win) => self.handle_title_change(win),
+            BackendEvent::ClientListChanged => {
+                self.handle_client_list_change();
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Clone for KeyboardLayoutSwitcher {
+    fn clone(&self) -> Self {
+        Self {
+            config_path: self.config_path.clone(),
+            log_path: self.log_path.clone(),
+            config: Arc::clone(&self.config),
+            reevaluate: Arc::clone(&self.reevaluate),
+            shutdown: Arc::clone(&self.shutdown),
+            input: Arc::clone(&self.input),
+            cache: Arc::clone(&self.cache),
+            temporary_layout: Arc::clone(&self.temporary_layout),
+            backend: Arc::clone(&self.backend),
+        }
+    }
+}
+
+/// Single-quotes a value for sh, so `$`, backticks and quotes stay literal.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::window::WindowSource;
+    use rdev::Key;
+
+    /// Records every group switch, windows are only known by their title.
+    struct FakeBackend {
+        titles: Mutex<HashMap<u32, String>>,
+        switches: Mutex<Vec<u8>>,
+        keymap: Mutex<Vec<(String, String)>>,
+        clients: Mutex<Vec<u32>>,
+    }
+
+    impl Default for FakeBackend {
+        fn default() -> Self {
+            Self {
+                titles: Mutex::default(),
+                switches: Mutex::default(),
+                keymap: Mutex::new(vec![
+                    ("us".into(), String::new()),
+                    ("ru".into(), String::new()),
+                ]),
+                clients: Mutex::default(),
+            }
+        }
+    }
+
+    impl FakeBackend {
+        fn set_title(&self, window_id: u32, title: &str) {
+            self.titles.lock().unwrap().insert(window_id, title.into());
+        }
+
+        fn switches(&self) -> Vec<u8> {
+            self.switches.lock().unwrap().clone()
+        }
+    }
+
+    impl WindowSource for FakeBackend {
+        fn window_class_pair(&self, _window_id: u32) -> Option<WindowClass> {
+            None
+        }
+
+        fn window_title(&self, window_id: u32) -> Option<String> {
+            self.titles.lock().unwrap().get(&window_id).cloned()
+        }
+    }
+
+    impl LayoutBackend for FakeBackend {
+        fn current_layout(&self) -> Result<u8> {
+            Ok(self.switches().last().copied().unwrap_or_default())
+        }
+
+        fn set_layout(&self, group: u8) -> Result<()> {
+            self.switches.lock().unwrap().push(group);
+            Ok(())
+        }
+
+        fn set_layout_on(&self, _device_id: u16, group: u8) -> Result<()> {
+            self.set_layout(group)
+        }
+
+        fn keycode_of(&self, _key: Key) -> Result<Option<u8>> {
+            Ok(None)
+        }
+
+        fn set_layout_with_keys(&self, group: u8, _keys: &[Key]) -> Result<()> {
+            self.set_layout(group)
+        }
+
+        fn group_count(&self) -> Result<u8> {
+            Ok(u8::try_from(self.keymap.lock().unwrap().len())?)
+        }
+
+        fn layout_symbols(&self) -> Result<Vec<String>> {
+            Ok(vec!["us".into(), "ru".into()])
+        }
+
+        fn layout_names(&self) -> Result<Vec<String>> {
+            Ok(vec!["English (US)".into(), "Russian".into()])
+        }
+
+        fn resolve_layout_name(&self, name: &str) -> Option<u8> {
+            ["us", "ru"]
+                .iter()
+                .position(|symbol| *symbol == name)
+                .and_then(|index| u8::try_from(index).ok())
+        }
+
+        fn keymap_layouts(&self) -> Result<Vec<(String, String)>> {
+            Ok(self.keymap.lock().unwrap().clone())
+        }
+
+        fn load_keymap_layouts(&self, layouts: &[(String, String)]) -> Result<()> {
+            *self.keymap.lock().unwrap() = layouts.to_vec();
+            Ok(())
+        }
+
+        fn active_window(&self) -> Option<u32> {
+            None
+        }
+
+        fn client_windows(&self) -> Vec<u32> {
+            self.clients.lock().unwrap().clone()
+        }
+
+        fn window_types(&self, _window_id: u32) -> Vec<String> {
+            vec!["normal".into()]
+        }
+
+        fn watch(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn next_event(&self, _timeout: Duration) -> Result<Option<BackendEvent>> {
+            Ok(None)
+        }
+    }
+
+    fn switcher(mut config: AppConfig, backend: Arc<FakeBackend>) -> KeyboardLayoutSwitcher {
+        config.compile_title_patterns();
+        KeyboardLayoutSwitcher {
+            config_path: PathBuf::from("config.json"),
+            log_path: PathBuf::from("kbd_switcher.log"),
+            config: Arc::new(Mutex::new(config)),
+            reevaluate: Arc::new(AtomicBool::new(false)),
+            shutdown: Arc::new(AtomicBool::new(false)),
+            input: Arc::new(InputState::default()),
+            cache: Arc::new(Mutex::new(LayoutCache::default())),
+            temporary_layout: Arc::new(Mutex::new(None)),
+            backend,
+        }
+    }
+
+    #[test]
+    fn status_line_formats() {
+        let backend = FakeBackend::default();
+        backend.set_layout(1).unwrap();
+        let line = |format| KeyboardLayoutSwitcher::status_line(&backend, format).unwrap();
+        assert_eq!(line(StatusFormat::Index), "1");
+        assert_eq!(line(StatusFormat::Short), "RU");
+        assert_eq!(line(StatusFormat::Full), "Russian");
+    }
+
+    #[test]
+    fn retitling_the_focused_window_applies_title_mapping() {
+        let backend = Arc::new(FakeBackend::default());
+        let mut config = AppConfig::default();
+        config
+            .window_title_map
+            .insert("Google Docs$".into(), LayoutRef::Name("ru".into()));
+        let mut switcher = switcher(config, Arc::clone(&backend));
+
+        backend.set_title(1, "Inbox - Mozilla Firefox");
+        switcher
+            .handle_event(BackendEvent::FocusChanged(Some(1)))
+            .unwrap();
+        assert!(backend.switches().is_empty());
+
+        // A tab switch only retitles the window, focus stays where it was
+        backend.set_title(1, "Notes - Google Docs");
+        switcher.handle_event(BackendEvent::Retitled(1)).unwrap();
+        assert_eq!(backend.switches(), [1]);
+
+        // Retitling within the same mapping leaves a manual switch alone
+        switcher
+            .handle_event(BackendEvent::GroupChanged(0))
+            .unwrap();
+        backend.set_title(1, "Plan - Google Docs");
+        switcher.handle_event(BackendEvent::Retitled(1)).unwrap();
+        assert_eq!(backend.switches(), [1]);
+    }
+
+    #[test]
+    fn retitling_a_background_window_is_ignored() {
+        let backend = Arc::new(FakeBackend::default());
+        let mut config = AppConfig::default();
+        config
+            .window_title_map
+            .insert("Google Docs$".into(), LayoutRef::Index(1));
+        let mut switcher = switcher(config, Arc::clone(&backend));
+
+        backend.set_title(1, "Terminal");
+        switcher
+            .handle_event(BackendEvent::FocusChanged(Some(1)))
+            .unwrap();
+        backend.set_title(2, "Notes - Google Docs");
+        switcher.handle_event(BackendEvent::Retitled(2)).unwrap();
+        assert!(backend.switches().is_empty());
+    }
+
+    /// A switch with the user's own key: XKB changes first, then reports it.
+    fn user_switch(switcher: &mut KeyboardLayoutSwitcher, backend: &FakeBackend, group: u8) {
+        backend.set_layout(group).unwrap();
+        switcher
+            .handle_event(BackendEvent::GroupChanged(group))
+            .unwrap();
+    }
+
+    #[test]
+    fn closed_windows_are_forgotten() {
+        let backend = Arc::new(FakeBackend::default());
+        let config = AppConfig {
+            remember_per_window: true,
+            ..AppConfig::default()
+        };
+        let mut switcher = switcher(config, Arc::clone(&backend));
+
+        *backend.clients.lock().unwrap() = vec![1, 2];
+        for window_id in [1, 2] {
+            switcher
+                .handle_event(BackendEvent::FocusChanged(Some(window_id)))
+                .unwrap();
+            user_switch(&mut switcher, &backend, 1);
+        }
+        assert_eq!(switcher.cache().window_layouts.len(), 2);
+
+        *backend.clients.lock().unwrap() = vec![2];
+        switcher
+            .handle_event(BackendEvent::ClientListChanged)
+            .unwrap();
+        assert_eq!(switcher.cache().window_layouts, HashMap::from([(2, 1)]));
+    }
+
+    #[test]
+    fn groups_of_a_removed_temporary_layout_are_forgotten() {
+        let backend = Arc::new(FakeBackend::default());
+        let mut config = AppConfig {
+            remember_per_window: true,
+            ..AppConfig::default()
+        };
+        config
+            .window_title_map
+            .insert("LibreOffice$".into(), LayoutRef::from_arg("de(nodeadkeys)"));
+        let mut switcher = switcher(config, Arc::clone(&backend));
+
+        backend.set_title(1, "Terminal");
+        backend.set_title(2, "Letter - LibreOffice");
+        switcher
+            .handle_event(BackendEvent::FocusChanged(Some(1)))
+            .unwrap();
+        user_switch(&mut switcher, &backend, 1);
+        switcher
+            .handle_event(BackendEvent::FocusChanged(Some(2)))
+            .unwrap();
+        assert_eq!(backend.switches().last(), Some(&2));
+        switcher
+            .handle_event(BackendEvent::GroupChanged(2))
+            .unwrap();
+
+        // Leaving the window removes the temporary group again
+        switcher
+            .handle_event(BackendEvent::FocusChanged(Some(1)))
+            .unwrap();
+        assert_eq!(backend.group_count().unwrap(), 2);
+        assert_eq!(switcher.cache().window_layouts, HashMap::from([(1, 1)]));
+    }
+
+    #[test]
+    fn shell_quote_keeps_values_literal() {
+        assert_eq!(shell_quote("/opt/nskbd"), "'/opt/nskbd'");
+        assert_eq!(shell_quote("it's $HOME `id`"), "'it'\\''s $HOME `id`'");
+    }
+}
@@ -0,0 +1,214 @@
+use log::error;
+use std::{fmt, sync::Arc};
+use x11rb::{protocol::xproto::*, rust_connection::RustConnection};
+
+x11rb::atom_manager! {
+    pub Atoms: AtomsCookie {
+        WM_CLASS,
+        _NET_ACTIVE_WINDOW,
+        _NET_CLIENT_LIST,
+        _NET_WM_NAME,
+        _NET_WM_WINDOW_TYPE,
+        UTF8_STRING,
+        _XKB_RULES_NAMES,
+    }
+}
+
+/// Both WM_CLASS strings, lowercased. `class` falls back to the instance for
+/// windows that only set the latter.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WindowClass {
+    pub instance: String,
+    pub class: String,
+}
+
+impl WindowClass {
+    /// The `instance.class` form of a `window_layout_map` key.
+    pub fn qualified(&self) -> Option<String> {
+        (!self.instance.is_empty()).then(|| format!("{}.{}", self.instance, self.class))
+    }
+}
+
+impl fmt::Display for WindowClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.qualified() {
+            Some(qualified) => write!(f, "{}", qualified),
+            None => write!(f, "{}", self.class),
+        }
+    }
+}
+
+/// Window properties the layout mapping needs. Implemented over X11 by
+/// `X11Windows` and by fakes in tests.
+pub trait WindowSource {
+    fn window_class_pair(&self, window_id: u32) -> Option<WindowClass>;
+    fn window_title(&self, window_id: u32) -> Option<String>;
+}
+
+#[derive(Clone)]
+pub struct X11Windows {
+    conn: Arc<RustConnection>,
+    root: Window,
+    atoms: Atoms,
+}
+
+impl X11Windows {
+    pub fn new(conn: Arc<RustConnection>, root: Window, atoms: Atoms) -> Self {
+        Self { conn, root, atoms }
+    }
+
+    /// The active window on the default screen.
+    pub fn active_window(&self) -> Option<u32> {
+        self.active_window_on(self.root)
+    }
+
+    /// Every screen has its own root with its own `_NET_ACTIVE_WINDOW`.
+    pub fn active_window_on(&self, root: Window) -> Option<u32> {
+        let reply = self
+            .conn
+            .get_property::<u32, u32>(
+                false,
+                root,
+                self.atoms._NET_ACTIVE_WINDOW,
+                AtomEnum::WINDOW.into(),
+                0,
+                1,
+            )
+            .ok()?
+            .reply()
+            .ok()?;
+
+        if reply.format == 32 && !reply.value.is_empty() {
+            Some(u32::from_ne_bytes([
+                reply.value[0],
+                reply.value[1],
+                reply.value[2],
+                reply.value[3],
+            ]))
+            .filter(|&window_id| window_id != x11rb::NONE)
+        } else {
+            None
+        }
+    }
+
+    /// Managed windows on the given screen, empty when the window manager does
+    /// not keep `_NET_CLIENT_LIST`.
+    pub fn client_windows_on(&self, root: Window) -> Vec<u32> {
+        self.conn
+            .get_property(
+                false,
+                root,
+                self.atoms._NET_CLIENT_LIST,
+                AtomEnum::WINDOW,
+                0,
+                4096,
+            )
+            .ok()
+            .and_then(|cookie| cookie.reply().ok())
+            .and_then(|reply| Some(reply.value32()?.collect()))
+            .unwrap_or_default()
+    }
+
+    /// EWMH window types, lowercased and without the `_NET_WM_WINDOW_TYPE_`
+    /// prefix. Windows without the property count as normal, as the spec says.
+    pub fn window_types(&self, window_id: u32) -> Vec<String> {
+        let atoms: Vec<Atom> = self
+            .conn
+            .get_property(
+                false,
+                window_id,
+                self.atoms._NET_WM_WINDOW_TYPE,
+                AtomEnum::ATOM,
+                0,
+                32,
+            )
+            .ok()
+            .and_then(|cookie| cookie.reply().ok())
+            .and_then(|reply| Some(reply.value32()?.collect()))
+            .unwrap_or_default();
+
+        if atoms.is_empty() {
+            return vec!["normal".to_string()];
+        }
+
+        atoms
+            .into_iter()
+            .filter_map(|atom| {
+                let reply = self.conn.get_atom_name(atom).ok()?.reply().ok()?;
+                let name = String::from_utf8_lossy(&reply.name);
+                let name = name.strip_prefix("_NET_WM_WINDOW_TYPE_").unwrap_or(&name);
+                Some(name.to_lowercase())
+            })
+            .collect()
+    }
+}
+
+impl WindowSource for X11Windows {
+    fn window_class_pair(&self, window_id: u32) -> Option<WindowClass> {
+        let reply = self
+            .conn
+            .get_property::<u32, u32>(
+                false,
+                window_id,
+                self.atoms.WM_CLASS,
+                AtomEnum::STRING.into(),
+                0,
+                1024,
+            )
+            .ok()?
+            .reply()
+            .ok()?;
+
+        if reply.format != 8 || reply.value.is_empty() {
+            error!("WM_CLASS property error for window {}", window_id);
+            return None;
+        }
+
+        let value = String::from_utf8_lossy(&reply.value);
+        let parts: Vec<&str> = value.split('\0').collect();
+
+        if parts.len() < 2 {
+            error!("WM_CLASS format error for window {}", window_id);
+            return None;
+        }
+
+        let class = if !parts[1].is_empty() {
+            parts[1]
+        } else {
+            parts[0]
+        };
+
+        if class.is_empty() {
+            error!("Empty window class for window {}", window_id);
+            return None;
+        }
+
+        Some(WindowClass {
+            instance: parts[0].to_lowercase(),
+            class: class.to_lowercase(),
+        })
+    }
+
+    /// `_NET_WM_NAME`, or the legacy `WM_NAME` for windows that only set that.
+    fn window_title(&self, window_id: u32) -> Option<String> {
+        [
+            (self.atoms._NET_WM_NAME, self.atoms.UTF8_STRING),
+            (AtomEnum::WM_NAME.into(), AtomEnum::STRING.into()),
+        ]
+        .into_iter()
+        .find_map(|(property, type_)| {
+            let reply = self
+                .conn
+                .get_property::<u32, u32>(false, window_id, property, type_, 0, 1024)
+                .ok()?
+                .reply()
+                .ok()?;
+
+            if reply.format != 8 || reply.value.is_empty() {
+                return None;
+            }
+
+            Some(String::from_utf8_lossy(&reply.value).into_owned())
+        })
+    }
+}
@@ -0,0 +1,469 @@
+use crate::error::FatalError;
+use anyhow::{anyhow, Context, Result};
+use rdev::Key;
+use std::{sync::Arc, thread, time::Duration};
+use x11rb::{
+    connection::Connection,
+    protocol::{
+        xkb::{
+            ConnectionExt as XkbConnectionExt, EventType as XkbEventType, Group, MapPart,
+            NameDetail, SelectEventsAux, SelectEventsAuxStateNotify, StatePart,
+        },
+        xproto::*,
+        xtest::ConnectionExt as XtestConnectionExt,
+    },
+    rust_connection::RustConnection,
+};
+
+pub const MAX_XKB_GROUPS: usize = 4;
+
+/// Components of the XKB symbols string that describe options rather than layouts.
+const NON_LAYOUT_SYMBOLS: &[&str] = &[
+    "pc",
+    "inet",
+    "group",
+    "ctrl",
+    "compose",
+    "level3",
+    "level5",
+    "lv3",
+    "lv5",
+    "altwin",
+    "capslock",
+    "shift",
+    "keypad",
+    "kpdl",
+    "nbsp",
+    "terminate",
+    "srvr_ctrl",
+    "eurosign",
+    "rupeesign",
+    "japan",
+    "korean",
+    "caps",
+    "numpad",
+    "evdev",
+    "typo",
+    "apple",
+    "mac",
+];
+
+/// X keysyms of the keys that have a hotkey name, used to find their keycodes.
+/// AltGr is either Alt_R or ISO_Level3_Shift depending on the layout options.
+const KEY_KEYSYMS: &[(Key, u32)] = &[
+    (Key::KeyA, 0x0061),
+    (Key::KeyB, 0x0062),
+    (Key::KeyC, 0x0063),
+    (Key::KeyD, 0x0064),
+    (Key::KeyE, 0x0065),
+    (Key::KeyF, 0x0066),
+    (Key::KeyG, 0x0067),
+    (Key::KeyH, 0x0068),
+    (Key::KeyI, 0x0069),
+    (Key::KeyJ, 0x006a),
+    (Key::KeyK, 0x006b),
+    (Key::KeyL, 0x006c),
+    (Key::KeyM, 0x006d),
+    (Key::KeyN, 0x006e),
+    (Key::KeyO, 0x006f),
+    (Key::KeyP, 0x0070),
+    (Key::KeyQ, 0x0071),
+    (Key::KeyR, 0x0072),
+    (Key::KeyS, 0x0073),
+    (Key::KeyT, 0x0074),
+    (Key::KeyU, 0x0075),
+    (Key::KeyV, 0x0076),
+    (Key::KeyW, 0x0077),
+    (Key::KeyX, 0x0078),
+    (Key::KeyY, 0x0079),
+    (Key::KeyZ, 0x007a),
+    (Key::Num0, 0x0030),
+    (Key::Num1, 0x0031),
+    (Key::Num2, 0x0032),
+    (Key::Num3, 0x0033),
+    (Key::Num4, 0x0034),
+    (Key::Num5, 0x0035),
+    (Key::Num6, 0x0036),
+    (Key::Num7, 0x0037),
+    (Key::Num8, 0x0038),
+    (Key::Num9, 0x0039),
+    (Key::F1, 0xffbe),
+    (Key::F2, 0xffbf),
+    (Key::F3, 0xffc0),
+    (Key::F4, 0xffc1),
+    (Key::F5, 0xffc2),
+    (Key::F6, 0xffc3),
+    (Key::F7, 0xffc4),
+    (Key::F8, 0xffc5),
+    (Key::F9, 0xffc6),
+    (Key::F10, 0xffc7),
+    (Key::F11, 0xffc8),
+    (Key::F12, 0xffc9),
+    (Key::Space, 0x0020),
+    (Key::Return, 0xff0d),
+    (Key::Tab, 0xff09),
+    (Key::Backspace, 0xff08),
+    (Key::Escape, 0xff1b),
+    (Key::Insert, 0xff63),
+    (Key::Delete, 0xffff),
+    (Key::Home, 0xff50),
+    (Key::End, 0xff57),
+    (Key::PageUp, 0xff55),
+    (Key::PageDown, 0xff56),
+    (Key::UpArrow, 0xff52),
+    (Key::DownArrow, 0xff54),
+    (Key::LeftArrow, 0xff51),
+    (Key::RightArrow, 0xff53),
+    (Key::Minus, 0x002d),
+    (Key::Equal, 0x003d),
+    (Key::Comma, 0x002c),
+    (Key::Dot, 0x002e),
+    (Key::Slash, 0x002f),
+    (Key::BackSlash, 0x005c),
+    (Key::SemiColon, 0x003b),
+    (Key::Quote, 0x0027),
+    (Key::BackQuote, 0x0060),
+    (Key::LeftBracket, 0x005b),
+    (Key::RightBracket, 0x005d),
+    (Key::Kp0, 0xffb0),
+    (Key::Kp1, 0xffb1),
+    (Key::Kp2, 0xffb2),
+    (Key::Kp3, 0xffb3),
+    (Key::Kp4, 0xffb4),
+    (Key::Kp5, 0xffb5),
+    (Key::Kp6, 0xffb6),
+    (Key::Kp7, 0xffb7),
+    (Key::Kp8, 0xffb8),
+    (Key::Kp9, 0xffb9),
+    (Key::KpReturn, 0xff8d),
+    (Key::KpPlus, 0xffab),
+    (Key::KpMinus, 0xffad),
+    (Key::KpMultiply, 0xffaa),
+    (Key::KpDivide, 0xffaf),
+    (Key::KpDelete, 0xff9f),
+    (Key::CapsLock, 0xffe5),
+    (Key::NumLock, 0xff7f),
+    (Key::ScrollLock, 0xff14),
+    (Key::PrintScreen, 0xff61),
+    (Key::Pause, 0xff13),
+    (Key::ShiftLeft, 0xffe1),
+    (Key::ShiftRight, 0xffe2),
+    (Key::ControlLeft, 0xffe3),
+    (Key::ControlRight, 0xffe4),
+    (Key::Alt, 0xffe9),
+    (Key::AltGr, 0xffea),
+    (Key::AltGr, 0xfe03),
+    (Key::MetaLeft, 0xffeb),
+    (Key::MetaRight, 0xffec),
+];
+
+#[derive(Clone)]
+pub struct XKeyboard {
+    conn: Arc<RustConnection>,
+    device_id: u16,
+}
+
+impl XKeyboard {
+    pub fn new(conn: Arc<RustConnection>, device_id: u16) -> Result<Self> {
+        let reply = conn
+            .xkb_use_extension(1, 0)
+            .context("Failed to initialize XKB extension")
+            .and_then(|cookie| cookie.reply().context("Failed to get XKB extension reply"))
+            .context(FatalError::XkbUnsupported)?;
+
+        if !reply.supported {
+            return Err(anyhow!(
+                "Server supports XKB {}.{}",
+                reply.server_major,
+                reply.server_minor
+            )
+            .context(FatalError::XkbUnsupported));
+        }
+
+        Ok(Self { conn, device_id })
+    }
+
+    pub fn current_layout(&self) -> Result<u8> {
+        self.current_layout_on(self.device_id)
+    }
+
+    fn current_layout_on(&self, device_id: u16) -> Result<u8> {
+        let state = self
+            .conn
+            .xkb_get_state(device_id)
+            .context("Failed to get XKB state")?
+            .reply()
+            .context("Failed to get XKB state reply")?;
+        Ok(state.group.into())
+    }
+
+    pub fn group_count(&self) -> Result<u8> {
+        let controls = self
+            .conn
+            .xkb_get_controls(self.device_id)
+            .context("Failed to get XKB controls")?
+            .reply()
+            .context("Failed to get XKB controls reply")?;
+        Ok(controls.num_groups)
+    }
+
+    /// Full group names as shown by desktop environments, e.g. "English (US)".
+    pub fn layout_names(&self) -> Result<Vec<String>> {
+        let names = self
+            .conn
+            .xkb_get_names(self.device_id, NameDetail::GROUP_NAMES)
+            .context("Failed to get XKB names")?
+            .reply()
+            .context("Failed to get XKB names reply")?;
+
+        names
+            .value_list
+            .groups
+            .unwrap_or_default()
+            .into_iter()
+            .map(|atom| self.atom_name(atom))
+            .collect()
+    }
+
+    /// Short layout names in group order, e.g. ["us", "ru"], taken from the
+    /// symbols component of the keymap.
+    pub fn layout_symbols(&self) -> Result<Vec<String>> {
+        let names = self
+            .conn
+            .xkb_get_names(self.device_id, NameDetail::SYMBOLS)
+            .context("Failed to get XKB names")?
+            .reply()
+            .context("Failed to get XKB names reply")?;
+
+        let symbols = match names.value_list.symbols_name {
+            Some(atom) => self.atom_name(atom)?,
+            None => return Ok(Vec::new()),
+        };
+
+        Ok(Self::parse_symbols(&symbols))
+    }
+
+    pub fn resolve_layout_name(&self, name: &str) -> Option<u8> {
+        let position = |names: Vec<String>| {
+            names
+                .iter()
+                .position(|candidate| candidate.eq_ignore_ascii_case(name))
+        };
+
+        let index = self
+            .layout_symbols()
+            .ok()
+            .and_then(position)
+            .or_else(|| self.layout_names().ok().and_then(position))?;
+
+        u8::try_from(index).ok()
+    }
+
+    fn parse_symbols(symbols: &str) -> Vec<String> {
+        let mut layouts: Vec<String> = Vec::new();
+
+        for component in symbols.split('+') {
+            let (component, group) = match component.split_once(':') {
+                Some((component, group)) => (component, group.parse::<usize>().ok()),
+                None => (component, None),
+            };
+            let name = component.split('(').next().unwrap_or_default();
+
+            let group = match group {
+                Some(group) => group,
+                None if layouts.is_empty() && !NON_LAYOUT_SYMBOLS.contains(&name) => 1,
+                None => continue,
+            };
+
+            if group == 0 || name.is_empty() {
+                continue;
+            }
+            if layouts.len() < group {
+                layouts.resize(group, String::new());
+            }
+            layouts[group - 1] = name.to_string();
+        }
+
+        layouts
+    }
+
+    fn atom_name(&self, atom: Atom) -> Result<String> {
+        let reply = self
+            .conn
+            .get_atom_name(atom)
+            .context("Failed to get atom name")?
+            .reply()
+            .context("Failed to get atom name reply")?;
+        Ok(String::from_utf8_lossy(&reply.name).into_owned())
+    }
+
+    /// Asks for `StateNotify` events on group changes, including ones made with
+    /// the user's own switch shortcut. This does not touch the root window
+    /// event mask.
+    pub fn select_group_events(&self) -> Result<()> {
+        let details = SelectEventsAux::new().state_notify(SelectEventsAuxStateNotify {
+            affect_state: StatePart::GROUP_STATE,
+            state_details: StatePart::GROUP_STATE,
+        });
+
+        self.conn
+            .xkb_select_events(
+                self.device_id,
+                XkbEventType::from(0u16),
+                XkbEventType::from(0u16),
+                MapPart::from(0u16),
+                MapPart::from(0u16),
+                &details,
+            )
+            .context("Failed to select XKB events")?;
+        Ok(())
+    }
+
+    pub fn set_layout(&self, group_num: u8) -> Result<()> {
+        self.set_layout_on(self.device_id, group_num)
+    }
+
+    /// Presses the user's own group-switching combo until the wanted group is
+    /// active. Only works if the combo is configured in the XKB options.
+    pub fn set_layout_with_keys(&self, group_num: u8, keys: &[Key]) -> Result<()> {
+        let keycodes = keys
+            .iter()
+            .map(|&key| {
+                self.key_to_keycode(key)?
+                    .with_context(|| format!("Key {:?} is not on the keyboard", key))
+            })
+            .collect::<Result<Vec<Keycode>>>()?;
+
+        for _ in 0..self.group_count()? {
+            if self.current_layout()? == group_num {
+                return Ok(());
+            }
+
+            for &keycode in &keycodes {
+                self.conn
+                    .xtest_fake_input(KEY_PRESS_EVENT, keycode, 0, x11rb::NONE, 0, 0, 0)
+                    .context("Failed to send XTEST key press")?;
+            }
+            for &keycode in keycodes.iter().rev() {
+                self.conn
+                    .xtest_fake_input(KEY_RELEASE_EVENT, keycode, 0, x11rb::NONE, 0, 0, 0)
+                    .context("Failed to send XTEST key release")?;
+            }
+
+            self.conn
+                .flush()
+                .context("Failed to flush X11 connection")?;
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        if self.current_layout()? == group_num {
+            Ok(())
+        } else {
+            Err(anyhow!("Switch keys did not reach group {}", group_num))
+        }
+    }
+
+    /// rdev reports keys it has no name for with their X keycode, named keys
+    /// are looked up by keysym in the current keyboard mapping.
+    pub fn key_to_keycode(&self, key: Key) -> Result<Option<Keycode>> {
+        if let Key::Unknown(keycode) = key {
+            return Ok(u8::try_from(keycode).ok());
+        }
+
+        let keysyms: Vec<u32> = KEY_KEYSYMS
+            .iter()
+            .filter(|(candidate, _)| *candidate == key)
+            .map(|&(_, keysym)| keysym)
+            .collect();
+        if keysyms.is_empty() {
+            return Ok(None);
+        }
+
+        let setup = self.conn.setup();
+        let count = setup.max_keycode - setup.min_keycode + 1;
+        let mapping = self
+            .conn
+            .get_keyboard_mapping(setup.min_keycode, count)
+            .context("Failed to get keyboard mapping")?
+            .reply()
+            .context("Failed to get keyboard mapping reply")?;
+
+        let per_keycode = usize::from(mapping.keysyms_per_keycode).max(1);
+        Ok(mapping
+            .keysyms
+            .iter()
+            .position(|candidate| keysyms.contains(candidate))
+            .and_then(|index| u8::try_from(index / per_keycode).ok())
+            .map(|offset| setup.min_keycode + offset))
+    }
+
+    pub fn set_layout_on(&self, device_id: u16, group_num: u8) -> Result<()> {
+        for _ in 1..=3 {
+            self.conn
+                .xkb_latch_lock_state(
+                    device_id,
+                    ModMask::from(0u8),
+                    ModMask::from(0u8),
+                    true,
+                    Group::from(group_num),
+                    ModMask::from(0u8),
+                    false,
+                    0,
+                )
+                .context("Failed to set XKB layout")?;
+
+            self.conn
+                .flush()
+                .context("Failed to flush X11 connection")?;
+            thread::sleep(Duration::from_millis(50));
+
+            match self.current_layout_on(device_id) {
+                Ok(new_layout) if new_layout == group_num => return Ok(()),
+                _ => continue,
+            }
+        }
+
+        Err(anyhow!("Layout switch failed after multiple attempts"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hotkey;
+
+    #[test]
+    fn switch_keys_have_keysyms() {
+        let keys =
+            hotkey::combo_keys("lshift rshift lctrl rctrl lalt ralt lsuper rsuper capslock space")
+                .unwrap();
+        for key in keys {
+            assert!(
+                KEY_KEYSYMS.iter().any(|(candidate, _)| *candidate == key),
+                "{:?}",
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn parses_layouts_in_group_order() {
+        assert_eq!(
+            XKeyboard::parse_symbols("pc+us+ru:2+inet(evdev)+group(alt_shift_toggle)"),
+            vec!["us", "ru"]
+        );
+    }
+
+    #[test]
+    fn strips_variants_and_fills_gaps() {
+        assert_eq!(
+            XKeyboard::parse_symbols("pc+de(nodeadkeys)+fr:3"),
+            vec!["de", "", "fr"]
+        );
+    }
+
+    #[test]
+    fn ignores_options_only_symbols() {
+        assert!(XKeyboard::parse_symbols("pc+inet(evdev)").is_empty());
+    }
+}